@@ -14,12 +14,53 @@ pub struct Config {
     pub rate_limit: RateLimitConfig,
     #[serde(default)]
     pub git_services: GitServicesConfig,
+    #[serde(default)]
+    pub redirect: RedirectConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub lfs: LfsConfig,
+    #[serde(default)]
+    pub upstream: UpstreamConfig,
+    #[serde(default)]
+    pub egress: EgressConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct ServerConfig {
     #[serde(default = "default_address")]
     pub address: SocketAddr,
+    // Reverse proxies (addresses or CIDR blocks) whose forwarding headers
+    // name the real client
+    #[serde(default = "default_trusted_proxies")]
+    pub trusted_proxies: Vec<String>,
+    // The header those proxies set. Any other is passed through from the
+    // client unchanged and so cannot be believed.
+    #[serde(default)]
+    pub client_ip_header: ClientIpHeader,
+    // Expect a PROXY protocol (v1 or v2) header on every connection from a
+    // trusted proxy, as sent by HAProxy or an L4 load balancer, and take the
+    // client address from it. Other peers are served without one.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+// Forwarding header a trusted proxy writes the client address to
+#[derive(Clone, Copy, Deserialize, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientIpHeader {
+    // Appended to by each proxy, e.g. nginx's $proxy_add_x_forwarded_for;
+    // also the source of X-Forwarded-Proto / X-Forwarded-Host
+    #[default]
+    XForwardedFor,
+    // RFC 7239, for= and its proto= / host= parameters
+    Forwarded,
+    // A single address the proxy overwrites
+    XRealIp,
 }
 
 #[derive(Clone, Deserialize, Default)]
@@ -38,14 +79,184 @@ pub struct CacheConfig {
     pub time_to_live: u64,
     #[serde(default = "default_cache_max_memory")]
     pub max_memory: usize,
+    #[serde(default)]
+    pub admission: AdmissionPolicy,
+    // Where entries live: this process's memory or a shared Redis server
+    #[serde(default)]
+    pub backend: CacheBackendKind,
+    #[serde(default)]
+    pub redis: RedisCacheConfig,
+    // Seconds between sweeps for expired entries; 0 disables the sweeper
+    #[serde(default = "default_cache_sweep_interval")]
+    pub sweep_interval: u64,
+    // Seconds an expired entry with an ETag or Last-Modified is kept for revalidation
+    #[serde(default = "default_cache_stale_retention")]
+    pub stale_retention: u64,
+    // Seconds past expiry an entry is served as is while it is refreshed in the background
+    #[serde(default)]
+    pub stale_while_revalidate: u64,
+    // Seconds past expiry an entry is served when upstream fails or is unreachable
+    #[serde(default = "default_cache_stale_if_error")]
+    pub stale_if_error: u64,
+    #[serde(default)]
+    pub disk: DiskCacheConfig,
+    // Per-URL overrides, first match wins
+    #[serde(default)]
+    pub rules: Vec<CacheRule>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CacheRule {
+    // A built-in URL class such as "github_releases" or "github_raw"
+    #[serde(default)]
+    pub class: Option<String>,
+    // Or a regex matched against the cache key
+    #[serde(default)]
+    pub pattern: Option<String>,
+    // TTL in seconds for matching responses
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    // Never cache matching URLs
+    #[serde(default)]
+    pub no_cache: bool,
+    // Apply this rule even if upstream Cache-Control says otherwise
+    #[serde(default)]
+    pub ignore_cache_control: bool,
+}
+
+// Persistent tier behind the in-memory cache
+#[derive(Clone, Deserialize)]
+pub struct DiskCacheConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_disk_cache_path")]
+    pub path: String,
+    // Byte budget for bodies on disk
+    #[serde(default = "default_disk_cache_max_size")]
+    pub max_size: u64,
+    // Disk hits up to this size are also copied into memory
+    #[serde(default = "default_disk_cache_promote_max_size")]
+    pub promote_max_size: u64,
+}
+
+impl Default for DiskCacheConfig {
+    fn default() -> Self {
+        DiskCacheConfig {
+            enabled: false,
+            path: default_disk_cache_path(),
+            max_size: default_disk_cache_max_size(),
+            promote_max_size: default_disk_cache_promote_max_size(),
+        }
+    }
+}
+
+// Whether a new entry may push out the least recently used one. The memory
+// backend is sharded by key, so "least recently used" is per shard: eviction
+// starts with the oldest entry of the new entry's shard, and TinyLFU compares
+// against that entry only. LRU order across the whole cache is approximate.
+#[derive(Clone, Copy, Deserialize, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AdmissionPolicy {
+    // Approximate LRU: every new entry is admitted
+    #[default]
+    Always,
+    // Admit only entries requested more often recently than the eviction victim
+    TinyLfu,
+}
+
+#[derive(Clone, Copy, Deserialize, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackendKind {
+    #[default]
+    Memory,
+    // Shared by every instance pointing at the same server
+    Redis,
+}
+
+// Shared cache backend, used when `backend = "redis"`
+#[derive(Clone, Deserialize)]
+pub struct RedisCacheConfig {
+    #[serde(default = "default_redis_url")]
+    pub url: String,
+    // Prepended to every key so several deployments can share one server
+    #[serde(default = "default_redis_prefix")]
+    pub prefix: String,
+    // Larger responses are not sent to Redis
+    #[serde(default = "default_redis_max_object_size")]
+    pub max_object_size: usize,
+    // Milliseconds to wait for a connection or a reply before treating the cache as unavailable
+    #[serde(default = "default_redis_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for RedisCacheConfig {
+    fn default() -> Self {
+        RedisCacheConfig {
+            url: default_redis_url(),
+            prefix: default_redis_prefix(),
+            max_object_size: default_redis_max_object_size(),
+            timeout: default_redis_timeout(),
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, Default)]
 pub struct RateLimitConfig {
     #[serde(default = "default_rate_limit_enabled")]
     pub enabled: bool,
+    #[serde(default)]
+    pub algorithm: RateLimitAlgorithm,
+    // Sustained rate: the token refill rate, or the sliding window's limit
     #[serde(default = "default_rate_limit_requests_per_minute")]
     pub requests_per_minute: u32,
+    // Requests a token bucket lets through at once; requests_per_minute if unset
+    #[serde(default)]
+    pub burst: Option<u32>,
+    // Limits for particular clients in place of the above, first match wins
+    #[serde(default)]
+    pub overrides: Vec<RateLimitOverride>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RateLimitOverride {
+    // Client addresses or CIDR blocks
+    pub clients: Vec<String>,
+    pub requests_per_minute: u32,
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+#[derive(Clone, Copy, Deserialize, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    // Tokens refill continuously; an idle client can spend up to `burst` at once
+    #[default]
+    TokenBucket,
+    // Exact count of requests made in the last 60 seconds
+    SlidingWindow,
+}
+
+// Cache administration API
+#[derive(Clone, Deserialize)]
+pub struct AdminConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Path prefix the API is served under
+    #[serde(default = "default_admin_path")]
+    pub path: String,
+    // Bearer token required on every admin request
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            enabled: false,
+            path: default_admin_path(),
+            token: None,
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, Default)]
@@ -56,14 +267,187 @@ pub struct GitServicesConfig {
     pub bitbucket_enabled: bool,
 }
 
+#[derive(Clone, Deserialize)]
+pub struct RedirectConfig {
+    // Upstream redirects followed by the proxy itself; 0 hands every redirect back to the client
+    #[serde(default = "default_redirect_max_hops")]
+    pub max_hops: usize,
+    // Hosts besides the supported Git service URLs that redirects may lead to and
+    // that the proxy will serve. A leading dot also matches subdomains.
+    #[serde(default = "default_redirect_allowed_hosts")]
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        RedirectConfig {
+            max_hops: default_redirect_max_hops(),
+            allowed_hosts: default_redirect_allowed_hosts(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Default)]
+pub struct LimitsConfig {
+    // Largest upstream response the proxy will relay, in bytes; 0 disables the limit
+    #[serde(default)]
+    pub max_size: u64,
+    #[serde(default)]
+    pub action: SizeLimitAction,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct LfsConfig {
+    #[serde(default = "default_lfs_enabled")]
+    pub enabled: bool,
+    // Externally reachable base URL of the proxy used in rewritten LFS hrefs,
+    // e.g. "https://gh.example.com". When unset it comes from the forwarding
+    // headers of a trusted proxy; without those, hrefs are left alone.
+    #[serde(default)]
+    pub public_url: Option<String>,
+}
+
+impl Default for LfsConfig {
+    fn default() -> Self {
+        LfsConfig {
+            enabled: default_lfs_enabled(),
+            public_url: None,
+        }
+    }
+}
+
+// Upstream HTTP client settings. Durations are in seconds; 0 disables a timeout.
+#[derive(Clone, Deserialize)]
+pub struct UpstreamConfig {
+    #[serde(default = "default_upstream_connect_timeout")]
+    pub connect_timeout: u64,
+    // Longest wait for any single read, including the response headers
+    #[serde(default = "default_upstream_read_timeout")]
+    pub read_timeout: u64,
+    // Budget for the whole exchange, body included
+    #[serde(default)]
+    pub total_timeout: u64,
+    #[serde(default = "default_upstream_pool_max_idle_per_host")]
+    pub pool_max_idle_per_host: usize,
+    #[serde(default = "default_upstream_pool_idle_timeout")]
+    pub pool_idle_timeout: u64,
+    #[serde(default = "default_upstream_tcp_keepalive")]
+    pub tcp_keepalive: u64,
+    #[serde(default = "default_upstream_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    #[serde(default)]
+    pub http_version: HttpVersion,
+    // Replaces the client's User-Agent on upstream requests when set
+    #[serde(default)]
+    pub user_agent: Option<String>,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            connect_timeout: default_upstream_connect_timeout(),
+            read_timeout: default_upstream_read_timeout(),
+            total_timeout: 0,
+            pool_max_idle_per_host: default_upstream_pool_max_idle_per_host(),
+            pool_idle_timeout: default_upstream_pool_idle_timeout(),
+            tcp_keepalive: default_upstream_tcp_keepalive(),
+            tcp_nodelay: default_upstream_tcp_nodelay(),
+            http_version: HttpVersion::default(),
+            user_agent: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpVersion {
+    // HTTP/2 when negotiated through TLS ALPN, HTTP/1.1 otherwise
+    #[default]
+    Auto,
+    // Only speak HTTP/1.1
+    Http1,
+    // Speak HTTP/2 without negotiation (h2c on plain connections)
+    PriorKnowledge,
+}
+
+// Outbound proxies for upstream traffic. Proxy URLs take the form
+// scheme://[user:password@]host:port with scheme http, https, socks5 or socks5h.
+#[derive(Clone, Deserialize, Default)]
+pub struct EgressConfig {
+    // Proxy for every service without its own setting
+    #[serde(default)]
+    pub proxy: Option<String>,
+    // Hosts, domains (".example.com") or CIDRs reached without the proxy
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    // Per-service proxies; "direct" sends that service around any proxy
+    #[serde(default)]
+    pub github: Option<String>,
+    #[serde(default)]
+    pub gitlab: Option<String>,
+    #[serde(default)]
+    pub bitbucket: Option<String>,
+}
+
+// Retries for idempotent upstream requests (GET/HEAD without a body)
+#[derive(Clone, Deserialize)]
+pub struct RetryConfig {
+    #[serde(default = "default_retry_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_retry_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub base_delay_ms: u64,
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub max_delay_ms: u64,
+    // No retry is started once it would end past this budget, counted from the first attempt
+    #[serde(default = "default_retry_budget_ms")]
+    pub budget_ms: u64,
+    // Upstream statuses worth another attempt
+    #[serde(default = "default_retry_statuses")]
+    pub statuses: Vec<u16>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            enabled: default_retry_enabled(),
+            max_retries: default_retry_max_retries(),
+            base_delay_ms: default_retry_base_delay_ms(),
+            max_delay_ms: default_retry_max_delay_ms(),
+            budget_ms: default_retry_budget_ms(),
+            statuses: default_retry_statuses(),
+        }
+    }
+}
+
+// What to do with a response whose Content-Length exceeds `limits.max_size`
+#[derive(Clone, Copy, Deserialize, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SizeLimitAction {
+    // Send the client to the origin URL instead of proxying it
+    #[default]
+    Redirect,
+    // Answer 413 Payload Too Large
+    Reject,
+}
+
 // Default values
 fn default_address() -> SocketAddr {
     "127.0.0.1:4000".parse().expect("Invalid default address")
 }
 
+// Only a reverse proxy on the same host is believed by default
+fn default_trusted_proxies() -> Vec<String> {
+    vec!["127.0.0.0/8".to_string(), "::1".to_string()]
+}
+
 fn default_server() -> ServerConfig {
     ServerConfig {
         address: default_address(),
+        trusted_proxies: default_trusted_proxies(),
+        client_ip_header: ClientIpHeader::default(),
+        proxy_protocol: false,
     }
 }
 
@@ -83,6 +467,50 @@ fn default_cache_max_memory() -> usize {
     100 * 1024 * 1024 // 100MB
 }
 
+fn default_cache_sweep_interval() -> u64 {
+    60
+}
+
+fn default_cache_stale_retention() -> u64 {
+    3600
+}
+
+fn default_cache_stale_if_error() -> u64 {
+    3600
+}
+
+fn default_redis_url() -> String {
+    "redis://127.0.0.1:6379".to_string()
+}
+
+fn default_redis_prefix() -> String {
+    "gh-proxy:".to_string()
+}
+
+fn default_redis_max_object_size() -> usize {
+    8 * 1024 * 1024 // 8MB
+}
+
+fn default_redis_timeout() -> u64 {
+    500
+}
+
+fn default_disk_cache_path() -> String {
+    "cache".to_string()
+}
+
+fn default_disk_cache_max_size() -> u64 {
+    10 * 1024 * 1024 * 1024 // 10GB
+}
+
+fn default_disk_cache_promote_max_size() -> u64 {
+    1024 * 1024 // 1MB
+}
+
+fn default_admin_path() -> String {
+    "/_admin".to_string()
+}
+
 fn default_rate_limit_enabled() -> bool {
     true
 }
@@ -91,6 +519,74 @@ fn default_rate_limit_requests_per_minute() -> u32 {
     60
 }
 
+fn default_redirect_max_hops() -> usize {
+    5
+}
+
+fn default_redirect_allowed_hosts() -> Vec<String> {
+    [
+        "codeload.github.com",
+        "objects.githubusercontent.com",
+        "release-assets.githubusercontent.com",
+        "bbuseruploads.s3.amazonaws.com",
+    ]
+    .iter()
+    .map(|h| h.to_string())
+    .collect()
+}
+
+fn default_lfs_enabled() -> bool {
+    true
+}
+
+fn default_upstream_connect_timeout() -> u64 {
+    10
+}
+
+fn default_upstream_read_timeout() -> u64 {
+    60
+}
+
+fn default_upstream_pool_max_idle_per_host() -> usize {
+    32
+}
+
+fn default_upstream_pool_idle_timeout() -> u64 {
+    90
+}
+
+fn default_upstream_tcp_keepalive() -> u64 {
+    60
+}
+
+fn default_upstream_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_retry_enabled() -> bool {
+    true
+}
+
+fn default_retry_max_retries() -> u32 {
+    2
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    200
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    2000
+}
+
+fn default_retry_budget_ms() -> u64 {
+    10000
+}
+
+fn default_retry_statuses() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
 impl Default for Config {
     fn default() -> Self {
         Config {
@@ -101,15 +597,34 @@ impl Default for Config {
                 max_capacity: default_cache_max_capacity(),
                 time_to_live: default_cache_time_to_live(),
                 max_memory: default_cache_max_memory(),
+                admission: AdmissionPolicy::default(),
+                backend: CacheBackendKind::default(),
+                redis: RedisCacheConfig::default(),
+                sweep_interval: default_cache_sweep_interval(),
+                stale_retention: default_cache_stale_retention(),
+                stale_while_revalidate: 0,
+                stale_if_error: default_cache_stale_if_error(),
+                disk: DiskCacheConfig::default(),
+                rules: Vec::new(),
             },
             rate_limit: RateLimitConfig {
                 enabled: default_rate_limit_enabled(),
+                algorithm: RateLimitAlgorithm::default(),
                 requests_per_minute: default_rate_limit_requests_per_minute(),
+                burst: None,
+                overrides: Vec::new(),
             },
             git_services: GitServicesConfig {
                 gitlab_enabled: false,
                 bitbucket_enabled: false,
             },
+            redirect: RedirectConfig::default(),
+            limits: LimitsConfig::default(),
+            lfs: LfsConfig::default(),
+            upstream: UpstreamConfig::default(),
+            egress: EgressConfig::default(),
+            retry: RetryConfig::default(),
+            admin: AdminConfig::default(),
         }
     }
 }
@@ -123,6 +638,8 @@ impl Config {
         // Set defaults
         cfg = cfg.set_default("server.address", "127.0.0.1:4000")
             .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("server.client_ip_header", "x-forwarded-for")
+            .map_err(AppError::ConfigError)?;
         cfg = cfg.set_default("jsdelivr.enabled", false)
             .map_err(AppError::ConfigError)?;
         cfg = cfg.set_default("cache.enabled", true)
@@ -133,14 +650,58 @@ impl Config {
             .map_err(AppError::ConfigError)?;
         cfg = cfg.set_default("cache.max_memory", 104857600) // 100MB
             .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("cache.admission", "always")
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("cache.sweep_interval", 60)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("cache.stale_retention", 3600)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("cache.stale_while_revalidate", 0)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("cache.stale_if_error", 3600)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("cache.backend", "memory")
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("cache.redis.url", "redis://127.0.0.1:6379")
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("cache.redis.prefix", "gh-proxy:")
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("cache.disk.enabled", false)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("cache.disk.path", "cache")
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("admin.enabled", false)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("admin.path", "/_admin")
+            .map_err(AppError::ConfigError)?;
         cfg = cfg.set_default("rate_limit.enabled", true)
             .map_err(AppError::ConfigError)?;
         cfg = cfg.set_default("rate_limit.requests_per_minute", 60)
             .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("rate_limit.algorithm", "token_bucket")
+            .map_err(AppError::ConfigError)?;
         cfg = cfg.set_default("git_services.gitlab_enabled", false)
             .map_err(AppError::ConfigError)?;
         cfg = cfg.set_default("git_services.bitbucket_enabled", false)
             .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("redirect.max_hops", 5)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("limits.max_size", 0)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("limits.action", "redirect")
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("lfs.enabled", true)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("upstream.connect_timeout", 10)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("upstream.read_timeout", 60)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("upstream.total_timeout", 0)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("retry.enabled", true)
+            .map_err(AppError::ConfigError)?;
+        cfg = cfg.set_default("retry.max_retries", 2)
+            .map_err(AppError::ConfigError)?;
 
         let config = cfg.build().map_err(AppError::ConfigError)?;
         config.try_deserialize().map_err(AppError::ConfigError)
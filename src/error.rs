@@ -32,11 +32,38 @@ pub enum AppError {
     #[error("Cache error: {0}")]
     CacheError(String),
     
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+    
     #[error("Rate limit error: {0}")]
     RateLimitError(String),
     
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
+    
+    #[error("Redirect error: {0}")]
+    RedirectError(String),
+    
+    #[error("Response too large: {0}")]
+    PayloadTooLarge(String),
+    
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    
+    #[error("Upstream connect timeout: {0}")]
+    UpstreamConnectTimeout(String),
+    
+    #[error("Upstream read timeout: {0}")]
+    UpstreamReadTimeout(String),
+    
+    #[error("Upstream timeout: {0}")]
+    UpstreamTimeout(String),
+    
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    
+    #[error("Not found: {0}")]
+    NotFound(String),
 }
 
 impl IntoResponse for AppError {
@@ -50,8 +77,17 @@ impl IntoResponse for AppError {
             AppError::RegexError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Regex error"),
             AppError::AddrParseError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Address parse error"),
             AppError::CacheError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Cache error"),
+            AppError::RedisError(_) => (StatusCode::SERVICE_UNAVAILABLE, "Cache unavailable"),
             AppError::RateLimitError(_) => (StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded"),
             AppError::InvalidRequest(_) => (StatusCode::BAD_REQUEST, "Invalid request"),
+            AppError::RedirectError(_) => (StatusCode::BAD_GATEWAY, "Too many redirects"),
+            AppError::PayloadTooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, "Response too large"),
+            AppError::Forbidden(_) => (StatusCode::FORBIDDEN, "Forbidden"),
+            AppError::UpstreamConnectTimeout(_) => (StatusCode::GATEWAY_TIMEOUT, "Upstream connect timeout"),
+            AppError::UpstreamReadTimeout(_) => (StatusCode::GATEWAY_TIMEOUT, "Upstream read timeout"),
+            AppError::UpstreamTimeout(_) => (StatusCode::GATEWAY_TIMEOUT, "Upstream timeout"),
+            AppError::Unauthorized(_) => (StatusCode::UNAUTHORIZED, "Unauthorized"),
+            AppError::NotFound(_) => (StatusCode::NOT_FOUND, "Not found"),
         };
 
         let body = Json(json!({
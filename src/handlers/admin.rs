@@ -0,0 +1,229 @@
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+use tracing::info;
+
+use crate::{
+    config::Config,
+    error::{AppError, Result},
+    middleware::{cache_key, lfs_object_id},
+    models::AppCache,
+    services::RETRY_STATS,
+};
+
+// Entry selection shared by listing and purging
+#[derive(Deserialize, Default)]
+pub struct EntryFilter {
+    key: Option<String>,
+    prefix: Option<String>,
+    regex: Option<String>,
+    limit: Option<usize>,
+}
+
+enum Selector {
+    All,
+    Key(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl Selector {
+    fn from_filter(filter: &EntryFilter, config: &Config) -> Result<Self> {
+        match (&filter.key, &filter.prefix, &filter.regex) {
+            (None, None, None) => Ok(Selector::All),
+            // Keys and prefixes are written like proxied URLs and normalized the
+            // same way, so "github.com/a/b/blob/x" finds its stored raw URL
+            (Some(key), None, None) => Ok(Selector::Key(normalize(key, config))),
+            (None, Some(prefix), None) => Ok(Selector::Prefix(normalize(prefix, config))),
+            (None, None, Some(regex)) => Regex::new(regex)
+                .map(Selector::Regex)
+                .map_err(|e| AppError::InvalidRequest(format!("Invalid regex: {}", e))),
+            _ => Err(AppError::InvalidRequest("Use only one of key, prefix or regex".to_string())),
+        }
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            Selector::All => true,
+            Selector::Key(wanted) => key == wanted,
+            Selector::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            Selector::Regex(regex) => regex.is_match(key),
+        }
+    }
+}
+
+// A key or prefix as the cache middleware would store it. The request path's
+// leading slash is optional. LFS keys are not URLs and are taken as they are
+// listed.
+fn normalize(key: &str, config: &Config) -> String {
+    let key = key.trim_start_matches('/');
+    match lfs_object_id(key) {
+        Some(_) => key.to_string(),
+        None => cache_key(key, config),
+    }
+}
+
+// GET {admin}/cache/stats
+pub async fn stats(State((cache, _)): State<(AppCache, Config)>) -> Json<Value> {
+    let disk = cache.disk.as_ref().map(|disk| {
+        let (entries, bytes) = disk.usage();
+        json!({ "entries": entries, "bytes": bytes, "max_bytes": disk.max_size() })
+    });
+    Json(json!({
+        "enabled": cache.enabled,
+        "backend": cache.usage(),
+        "disk": disk,
+        "hits": cache.stats.hits.load(Ordering::Relaxed),
+        "misses": cache.stats.misses.load(Ordering::Relaxed),
+        "evictions": cache.stats.evictions.load(Ordering::Relaxed),
+        "rejections": cache.stats.rejections.load(Ordering::Relaxed),
+        "revalidations": cache.stats.revalidations.load(Ordering::Relaxed),
+        "expirations": cache.stats.expirations.load(Ordering::Relaxed),
+        "stale": cache.stats.stale.load(Ordering::Relaxed),
+        "upstream": {
+            "retries": RETRY_STATS.retries.load(Ordering::Relaxed),
+            "retries_exhausted": RETRY_STATS.exhausted.load(Ordering::Relaxed),
+        },
+    }))
+}
+
+// GET {admin}/cache/entries[?key=|prefix=|regex=][&limit=]
+pub async fn list_entries(
+    State((cache, config)): State<(AppCache, Config)>,
+    Query(filter): Query<EntryFilter>,
+) -> Result<Json<Value>> {
+    let selector = Selector::from_filter(&filter, &config)?;
+    let entries: Vec<_> = cache.entries().await?.into_iter().filter(|entry| selector.matches(&entry.key)).collect();
+    let count = entries.len();
+    let entries: Vec<_> = entries.into_iter().take(filter.limit.unwrap_or(1000)).collect();
+    Ok(Json(json!({ "count": count, "entries": entries })))
+}
+
+// DELETE {admin}/cache/entries?key=|prefix=|regex=
+pub async fn purge_entries(
+    State((cache, config)): State<(AppCache, Config)>,
+    Query(filter): Query<EntryFilter>,
+) -> Result<Json<Value>> {
+    let selector = match Selector::from_filter(&filter, &config)? {
+        Selector::All => {
+            return Err(AppError::InvalidRequest(
+                "Specify key, prefix or regex; use DELETE on the cache itself to flush".to_string(),
+            ))
+        }
+        selector => selector,
+    };
+
+    let purged = match &selector {
+        Selector::Key(key) => match cache.remove(key).await {
+            true => 1,
+            false => return Err(AppError::NotFound(format!("No cache entry for {}", key))),
+        },
+        selector => cache.remove_matching(|key| selector.matches(key)).await?,
+    };
+    info!("Admin purged {} cache entries", purged);
+    Ok(Json(json!({ "message": format!("Purged {} entries", purged), "purged": purged })))
+}
+
+// DELETE {admin}/cache
+pub async fn flush(State((cache, _)): State<(AppCache, Config)>) -> Result<Json<Value>> {
+    let purged = cache.remove_matching(|_| true).await?;
+    info!("Admin flushed the cache ({} entries)", purged);
+    Ok(Json(json!({ "message": format!("Flushed {} entries", purged), "purged": purged })))
+}
+
+// Unknown admin paths
+pub async fn not_found() -> AppError {
+    AppError::NotFound("Unknown admin endpoint".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CacheStats, MemoryBackend};
+    use axum::http::{HeaderMap, StatusCode};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    fn cache() -> AppCache {
+        let stats = Arc::new(CacheStats::default());
+        let backend = Arc::new(MemoryBackend::new(100, 1 << 20, Default::default(), stats.clone()));
+        AppCache::new(true, backend, stats, 3600)
+    }
+
+    fn filter(key: Option<&str>, prefix: Option<&str>) -> Query<EntryFilter> {
+        Query(EntryFilter {
+            key: key.map(str::to_string),
+            prefix: prefix.map(str::to_string),
+            ..Default::default()
+        })
+    }
+
+    async fn store(cache: &AppCache, key: String) {
+        assert!(cache.set(key, StatusCode::OK, HeaderMap::new(), 3600, Bytes::from_static(b"body")).await);
+    }
+
+    async fn listed(cache: &AppCache, config: &Config, prefix: Option<&str>) -> Vec<String> {
+        let Json(listing) = list_entries(State((cache.clone(), config.clone())), filter(None, prefix)).await.unwrap();
+        let mut keys: Vec<String> = listing["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["key"].as_str().unwrap().to_string())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    const OID: &str = "3c65ea93424a9c362fec0e3a69ea36031e8a358441479dd665cc6110eabe7b08";
+
+    async fn populated(config: &Config) -> AppCache {
+        let cache = cache();
+        for path in [
+            "github.com/owner/repo/blob/main/README.md",
+            "https://raw.githubusercontent.com/owner/repo/main/file.txt",
+            "https://github.com/owner/repo/releases/download/v1/tool.tar.gz",
+        ] {
+            store(&cache, cache_key(path, config)).await;
+        }
+        store(&cache, format!("lfs/{}", OID)).await;
+        cache
+    }
+
+    #[tokio::test]
+    async fn every_listed_key_can_be_purged() {
+        let config = Config::default();
+        let cache = populated(&config).await;
+
+        let keys = listed(&cache, &config, None).await;
+        assert_eq!(keys.len(), 4);
+        for key in &keys {
+            let Json(result) = purge_entries(State((cache.clone(), config.clone())), filter(Some(key), None))
+                .await
+                .unwrap_or_else(|e| panic!("purging {} failed: {}", key, e));
+            assert_eq!(result["purged"], 1, "purging {}", key);
+        }
+        assert!(listed(&cache, &config, None).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keys_and_prefixes_are_normalized_like_requests() {
+        let config = Config::default();
+        let cache = populated(&config).await;
+
+        assert_eq!(listed(&cache, &config, Some("lfs/")).await, [format!("lfs/{}", OID)]);
+        assert_eq!(listed(&cache, &config, Some("/github.com/owner/repo/blob/")).await.len(), 1);
+
+        let Json(result) = purge_entries(
+            State((cache.clone(), config.clone())),
+            filter(Some("github.com/owner/repo/blob/main/README.md"), None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["purged"], 1);
+    }
+}
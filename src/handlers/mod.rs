@@ -6,6 +6,7 @@ use axum::{
 
 use crate::error::{AppError, Result};
 
+pub mod admin;
 mod proxy;
 pub use proxy::handler;
 
@@ -30,10 +31,14 @@ pub fn handle_204() -> Result<Response> {
 
 // Handle redirect responses
 pub fn handle_redirect(query_string: String) -> Result<Response> {
-    let location = format!("/{}", query_string);
+    handle_redirect_to(&format!("/{}", query_string))
+}
+
+// Redirect to an absolute location, e.g. the origin URL
+pub fn handle_redirect_to(location: &str) -> Result<Response> {
     let mut res = Response::new(Body::empty());
     *res.status_mut() = StatusCode::FOUND;
-    let header_value = header::HeaderValue::from_str(&location)
+    let header_value = header::HeaderValue::from_str(location)
         .map_err(|_| AppError::InvalidRequest("Invalid redirect location".to_string()))?;
     res.headers_mut().insert(header::LOCATION, header_value);
     Ok(res)
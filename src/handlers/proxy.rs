@@ -1,9 +1,10 @@
 use axum::{
     body::Body,
-    extract::{Request, State},
+    extract::{ConnectInfo, Request, State},
     http::header,
     response::{IntoResponse, Response},
 };
+use std::net::SocketAddr;
 use tracing::{info, warn, debug};
 
 use crate::{
@@ -11,7 +12,7 @@ use crate::{
     error::AppError,
     handlers,
     services,
-    utils,
+    utils::{self, TrustedProxies},
 };
 
 // Extract git URL from request
@@ -22,18 +23,24 @@ fn get_git_url(req: &Request<Body>) -> String {
         .map(|v| v.as_str())
         .unwrap_or(req.uri().path());
 
-    let path = if path_query.starts_with("/") {
-        path_query[1..].into()
-    } else {
-        path_query
-    };
-    path.into()
+    path_query.strip_prefix('/').unwrap_or(path_query).into()
+}
+
+// Base URL under which clients reach the proxy, for absolute links we hand
+// out: the configured one, or what a trusted reverse proxy says the client
+// used. The Host header alone is the client's say-so, so it doesn't count.
+fn proxy_base_url(req: &Request<Body>, config: &Config, trusted_proxies: &TrustedProxies) -> Option<String> {
+    if let Some(public_url) = &config.lfs.public_url {
+        return Some(public_url.trim_end_matches('/').to_string());
+    }
+    let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+    utils::forwarded_origin(req.headers(), peer, trusted_proxies)
 }
 
 // Main request handler
 pub async fn handler(
-    State((client, config)): State<(reqwest::Client, Config)>,
-    req: Request<Body>,
+    State((clients, config, trusted_proxies)): State<(services::UpstreamClients, Config, TrustedProxies)>,
+    mut req: Request<Body>,
 ) -> Result<Response, AppError> {
     use axum::http::Method;
     
@@ -55,18 +62,29 @@ pub async fn handler(
     let path = get_git_url(&req);
 
     // Handle redirects
-    if path.starts_with("q=") {
-        info!("Handling redirect for query: {}", &path[2..]);
+    if let Some(query) = path.strip_prefix("q=") {
+        info!("Handling redirect for query: {}", query);
         return handlers::handle_redirect(path.replace("q=", ""));
     }
 
+    // Same canonical form the cache keys on
+    let path = utils::normalize_url(&path);
+
     if utils::is_supported_url(&path, &config) {
         info!("Processing supported URL: {}", path);
+
+        // The proxy is read-only: clones and fetches go through, pushes do not
+        if utils::is_git_push(&path) {
+            warn!("Refusing git push through proxy: {}", path);
+            return Err(AppError::Forbidden(
+                "git push (git-receive-pack) is not supported through the proxy".to_string(),
+            ));
+        }
         
         // Handle GitHub blob/raw URLs with jsDelivr if enabled
         if utils::GITHUB_BLOB_RAW.is_match(&path) && config.jsdelivr.enabled {
             info!("Redirecting to jsDelivr for GitHub blob URL");
-            let new_url = path.replacen("/blob/", "@", 1).replacen(
+            let new_url = path.trim_start_matches("https://").replacen("/blob/", "@", 1).replacen(
                 "github.com",
                 "https://gcore.jsdelivr.net/gh",
                 1,
@@ -77,8 +95,22 @@ pub async fn handler(
         // Process the URL appropriately based on the service
         let final_path = utils::process_url(path, &config);
         info!("Proxying request to: {}", final_path);
+        let client = clients.for_url(&final_path);
+
+        // LFS batch responses get their download links pointed at the proxy
+        if utils::is_lfs_batch(&final_path, &config) {
+            match proxy_base_url(&req, &config, &trusted_proxies) {
+                Some(proxy_base) => {
+                    // The JSON has to be readable to rewrite it
+                    req.headers_mut().remove(header::ACCEPT_ENCODING);
+                    let response = services::handle_proxy(req, client, final_path, &config).await?;
+                    return services::rewrite_lfs_batch(response, &proxy_base, &config).await;
+                }
+                None => warn!("Not rewriting LFS download links: set lfs.public_url or send X-Forwarded-Proto and X-Forwarded-Host from a trusted proxy"),
+            }
+        }
 
-        return services::handle_proxy(req, &client, final_path).await;
+        return services::handle_proxy(req, client, final_path, &config).await;
     }
 
     warn!("Unsupported URL requested: {}", path);
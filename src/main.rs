@@ -1,4 +1,22 @@
-use axum::Router;
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::Request,
+    routing::{delete, get},
+    Router,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tower::ServiceExt;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 // Import modules
@@ -10,6 +28,9 @@ mod services;
 mod utils;
 mod middleware;
 
+// How long a new connection may take to send its PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Initialize tracing
@@ -31,38 +52,147 @@ async fn main() -> anyhow::Result<()> {
                    config.rate_limit.enabled, 
                    config.rate_limit.requests_per_minute);
     
-    let cache = models::AppCache::with_memory_limit(
-        config.cache.enabled,
-        config.cache.max_capacity,
-        config.cache.max_memory,
-        config.cache.time_to_live,
-    );
+    let stats = Arc::new(models::CacheStats::default());
+    let backend: Arc<dyn models::CacheBackend> = match config.cache.backend {
+        config::CacheBackendKind::Redis if config.cache.enabled => {
+            tracing::info!("Using shared Redis cache at {}", config.cache.redis.url);
+            Arc::new(models::RedisBackend::connect(&config.cache.redis).await?)
+        }
+        _ => Arc::new(models::MemoryBackend::new(
+            config.cache.max_capacity,
+            config.cache.max_memory,
+            config.cache.admission,
+            stats.clone(),
+        )),
+    };
+    let mut cache = models::AppCache::new(config.cache.enabled, backend, stats, config.cache.time_to_live)
+    .with_stale_retention(config.cache.stale_retention)
+    .with_stale_serving(config.cache.stale_while_revalidate, config.cache.stale_if_error)
+    .with_policy(models::CachePolicy::new(&config.cache.rules, config.cache.time_to_live)?);
+    if config.cache.enabled && config.cache.disk.enabled {
+        let disk = models::DiskCache::open(
+            &config.cache.disk.path,
+            config.cache.disk.max_size,
+            config.cache.time_to_live,
+            config.cache.stale_retention,
+            cache.stale_grace(),
+            config.cache.disk.promote_max_size,
+        )?;
+        cache = cache.with_disk(disk);
+    }
+    if config.cache.enabled && config.cache.sweep_interval > 0 {
+        cache.spawn_sweeper(Duration::from_secs(config.cache.sweep_interval));
+    }
     
-    let rate_limiter = middleware::RateLimiter::new(
-        config.rate_limit.enabled,
-        config.rate_limit.requests_per_minute,
-    );
+    let rate_limiter = middleware::RateLimiter::new(&config.rate_limit, Arc::new(middleware::SystemClock))?;
+
+    let trusted_proxies = utils::TrustedProxies::new(&config.server.trusted_proxies)?
+        .with_header(config.server.client_ip_header);
 
-    // Create HTTP client
-    let client = reqwest::Client::new();
+    // Create HTTP clients, one per upstream service
+    let clients = services::UpstreamClients::new(&config)?;
 
-    // Create app with state
+    // Create app with state. The last layer added runs first, so every
+    // request, cache hits included, passes the rate limit
     let app = Router::new()
         .fallback(handlers::handler)
-        .route_layer(axum::middleware::from_fn_with_state(
-            rate_limiter.clone(),
-            middleware::rate_limit_middleware,
-        ))
-        .route_layer(axum::middleware::from_fn_with_state(
-            cache.clone(),
+        .layer(axum::middleware::from_fn_with_state(
+            (cache.clone(), middleware::InFlight::new(), config.clone()),
             middleware::cache_middleware,
         ))
-        .with_state((client, config.clone()));
+        .layer(axum::middleware::from_fn_with_state(
+            (rate_limiter.clone(), trusted_proxies.clone()),
+            middleware::rate_limit_middleware,
+        ))
+        .with_state((clients, config.clone(), trusted_proxies.clone()));
+
+    // The admin API sits outside the proxy's cache and rate limit layers
+    let app = if config.admin.enabled {
+        let token = config
+            .admin
+            .token
+            .clone()
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("admin.token must be set when the admin API is enabled"))?;
+        let admin = Router::new()
+            .route("/cache", delete(handlers::admin::flush))
+            .route("/cache/stats", get(handlers::admin::stats))
+            .route(
+                "/cache/entries",
+                get(handlers::admin::list_entries).delete(handlers::admin::purge_entries),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                token,
+                middleware::admin_auth_middleware,
+            ))
+            .fallback(handlers::admin::not_found)
+            .with_state((cache.clone(), config.clone()));
+        tracing::info!("Admin API enabled under {}", config.admin.path);
+        Router::new().nest(&config.admin.path, admin).merge(app)
+    } else {
+        app
+    };
 
     // Bind and serve
-    let listener = tokio::net::TcpListener::bind(config.server.address).await?;
+    let listener = TcpListener::bind(config.server.address).await?;
     tracing::info!("Listening on {}", config.server.address);
-    axum::serve(listener, app).await?;
+    if config.server.proxy_protocol {
+        tracing::info!("Expecting PROXY protocol headers on incoming connections");
+        serve_proxy_protocol(listener, app, trusted_proxies).await;
+    } else {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+    }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+// Serve connections that open with a PROXY protocol header, as if they came
+// straight from the client address it names. Only trusted proxies may send
+// one; anyone else could claim any address, so their connections are served
+// as plain HTTP under their own address. A trusted peer's connection without
+// a valid header is dropped: taking its first bytes for HTTP would be a guess.
+async fn serve_proxy_protocol(listener: TcpListener, app: Router, trusted_proxies: utils::TrustedProxies) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Typically out of file descriptors; give connections a moment to close
+                tracing::warn!("Failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let app = app.clone();
+        let trusted = trusted_proxies.contains(peer.ip());
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let header = match trusted {
+                true => tokio::time::timeout(PROXY_HEADER_TIMEOUT, utils::read_proxy_header(&mut stream)).await,
+                false => Ok(Ok(None)),
+            };
+            let client = match header {
+                Ok(Ok(client)) => client.unwrap_or(peer),
+                Ok(Err(e)) => {
+                    tracing::warn!("Dropping connection from {}: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    tracing::warn!("Dropping connection from {}: no PROXY protocol header in time", peer);
+                    return;
+                }
+            };
+            // Handlers and middleware see the client as the connection's peer
+            let service = app.map_request(move |request: Request<Incoming>| {
+                let mut request = request.map(Body::new);
+                request.extensions_mut().insert(ConnectInfo(client));
+                request
+            });
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service))
+                .await
+            {
+                tracing::debug!("Connection from {} closed with an error: {}", client, e);
+            }
+        });
+    }
+}
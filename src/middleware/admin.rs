@@ -0,0 +1,41 @@
+use axum::{
+    extract::State,
+    http::{header, Request},
+    middleware::Next,
+    response::Response,
+    body::Body,
+};
+use tracing::warn;
+
+use crate::error::AppError;
+
+// Require `Authorization: Bearer <token>` on admin requests
+pub async fn admin_auth_middleware(
+    State(token): State<String>,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response, AppError> {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    match presented {
+        Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => Ok(next.run(request).await),
+        Some(_) => {
+            warn!("Rejected admin request to {} with an invalid token", request.uri().path());
+            Err(AppError::Unauthorized("Invalid admin token".to_string()))
+        }
+        None => Err(AppError::Unauthorized("Missing bearer token".to_string())),
+    }
+}
+
+// Compare without returning early, so the token can't be guessed byte by byte from timings
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -1,19 +1,60 @@
 use axum::{
     extract::State,
-    http::{Request, Response},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode},
     middleware::Next,
     body::Body,
+    response::IntoResponse,
 };
-use tracing::info;
+use bytes::Bytes;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info, warn};
 
+use super::InFlight;
 use crate::{
+    config::Config,
     error::AppError,
-    models::AppCache,
+    models::{allows_authorized_storage, allows_stale, is_private, merge_headers, AppCache, CachedBody, CachedObject, DiskCache},
+    utils::{self, RangeRequest},
 };
 
+// Upstream response headers stored with an entry and replayed on hits.
+// Framing and per-connection headers are recomputed for every response.
+const CACHED_HEADERS: [HeaderName; 9] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_DISPOSITION,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LANGUAGE,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CACHE_CONTROL,
+    header::EXPIRES,
+    header::VARY,
+];
+
+// Request headers that identify the client to upstream
+const CREDENTIAL_HEADERS: [HeaderName; 2] = [header::AUTHORIZATION, header::COOKIE];
+
+// Request headers that make the response depend on more than the URL
+const TAILORING_HEADERS: [HeaderName; 4] = [header::RANGE, header::IF_RANGE, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE];
+
+// Cache keys of LFS objects: this followed by the object id
+const LFS_KEY_PREFIX: &str = "lfs/";
+
+static X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+// Warning values for expired entries served as is (RFC 7234, section 5.5)
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+const REVALIDATION_FAILED_WARNING: &str = "111 - \"Revalidation Failed\"";
+
 // Cache middleware
 pub async fn cache_middleware(
-    State(cache): State<AppCache>,
+    State((cache, flights, config)): State<(AppCache, InFlight, Config)>,
     request: Request<Body>,
     next: Next,
 ) -> Result<Response<Body>, AppError> {
@@ -23,54 +64,529 @@ pub async fn cache_middleware(
     }
 
     let path = get_request_path(&request);
-    
+
     // Skip cache for redirect requests (q= prefix)
     if path.starts_with("q=") {
         return Ok(next.run(request).await);
     }
 
+    // Ref advertisements change on every push and differ per protocol version
+    if utils::is_git_smart_http(&path) {
+        return Ok(next.run(request).await);
+    }
+
+    let path = cache_key(&path, &config);
+
+    if cache.policy.bypass(&path) {
+        info!("Cache rule excludes: {}", path);
+        return Ok(next.run(request).await);
+    }
+
     // Try to get from cache first
+    let mut stale = None;
+    let signed = lfs_object_id(&path).is_some();
     if cache.enabled {
-        if let Some(cached_data) = cache.get(&path) {
-            info!("Cache hit for: {}", path);
-            let response = Response::builder()
-                .status(axum::http::StatusCode::OK)
-                .header(axum::http::header::CONTENT_TYPE, "application/octet-stream")
-                .body(Body::from(cached_data))
-                .map_err(|e| AppError::CacheError(e.to_string()))?;
-            return Ok(response);
-        } else {
-            info!("Cache miss for: {}", path);
+        match cache.lookup(&path).await {
+            // The signature on the client's URL is the only access check, so
+            // upstream has to accept it before the copy is served
+            Some(object) if signed => {
+                info!("Cached LFS object, confirming access upstream: {}", path);
+                stale = Some(object);
+            }
+            Some(object) if !object.stale => {
+                info!("Cache hit for: {}", path);
+                if let CachedBody::Disk(file) = &object.body {
+                    promote(&cache, &path, &object, file);
+                }
+                match cached_response(request.headers(), object).await {
+                    Ok(response) => return Ok(response),
+                    // The file was evicted or deleted between lookup and open
+                    Err(e) => {
+                        warn!("Failed to serve cached object {}: {}", path, e);
+                        cache.remove(&path).await;
+                    }
+                }
+            }
+            // Within the stale-while-revalidate window: answer now, refresh behind the scenes
+            Some(object) if object.staleness() < cache.stale_while_revalidate && allows_stale(&object.headers) => {
+                info!("Serving stale entry while revalidating: {}", path);
+                revalidate(&cache, &flights, &path, object.clone(), &request, &next);
+                match stale_response(&cache, request.headers(), object, STALE_WARNING).await {
+                    Ok(response) => return Ok(response),
+                    Err(e) => {
+                        warn!("Failed to serve cached object {}: {}", path, e);
+                        cache.remove(&path).await;
+                    }
+                }
+            }
+            Some(object) => {
+                info!("Cache entry expired, revalidating: {}", path);
+                stale = Some(object);
+            }
+            None => info!("Cache miss for: {}", path),
         }
     } else {
         info!("Cache disabled, proceeding to handler for: {}", path);
     }
 
+    // Clients asking for the plain object share one upstream fetch. Range and
+    // conditional requests get answers tailored to them, so they fetch alone,
+    // as do credentialed requests and LFS downloads, each of which upstream
+    // has to authorise.
+    if cache.enabled && !signed && !is_tailored(request.headers()) && !is_credentialed(request.headers()) {
+        let shareable = shareable(&cache);
+        let fetch = fetch(cache, path.clone(), stale, request, next);
+        return Ok(flights
+            .run(path, async move { fetch.await.unwrap_or_else(IntoResponse::into_response) }, shareable)
+            .await);
+    }
+
+    fetch(cache, path, stale, request, next).await
+}
+
+// Fetch from upstream, revalidating `stale` if there is one, and store the result
+async fn fetch(
+    cache: AppCache,
+    path: String,
+    stale: Option<CachedObject>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Result<Response<Body>, AppError> {
+    // Ask upstream whether our expired copy is still current. The client's own
+    // conditional headers are answered from the cache afterwards.
+    let client_headers = stale.as_ref().map(|_| request.headers().clone());
+    let authorized = request.headers().contains_key(header::AUTHORIZATION);
+    if let Some(object) = &stale {
+        let headers = request.headers_mut();
+        headers.remove(header::IF_NONE_MATCH);
+        headers.remove(header::IF_MODIFIED_SINCE);
+        if let Some(etag) = object.headers.get(header::ETAG) {
+            headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = object.headers.get(header::LAST_MODIFIED) {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    // A stored copy is served to every client, so it has to be one they can
+    // all read: ask upstream for the identity encoding
+    if cache.enabled {
+        request.headers_mut().remove(header::ACCEPT_ENCODING);
+    }
+
     // Execute the next middleware/handler
-    let response = next.run(request).await;
+    let mut response = next.run(request).await;
+
+    if let (Some(mut object), Some(client_headers)) = (stale, client_headers) {
+        if response.status() == StatusCode::NOT_MODIFIED {
+            info!("Cache entry revalidated: {}", path);
+            let updated = cacheable_headers(response.headers());
+            merge_headers(&mut object.headers, &updated);
+            object.ttl = cache.policy.ttl(&path, &object.headers).unwrap_or(0);
+            object.timestamp = cache.refresh(&path, &updated, object.ttl).await;
+            return cached_response(&client_headers, object).await;
+        }
+        // stale-if-error: an outage should not take down what we already have
+        if response.status().is_server_error()
+            && lfs_object_id(&path).is_none()
+            && object.staleness() < cache.stale_if_error
+            && allows_stale(&object.headers)
+        {
+            warn!("Upstream answered {} for {}, serving stale copy", response.status(), path);
+            return stale_response(&cache, &client_headers, object, REVALIDATION_FAILED_WARNING).await;
+        }
+    }
+
+    if cache.enabled {
+        response.headers_mut().insert(X_CACHE.clone(), HeaderValue::from_static("MISS"));
+    }
+
+    let content_length = content_length(response.headers());
+    // Partial (206) responses to Range requests are never stored as the object,
+    // nor are responses that vary on request headers the key does not cover,
+    // or answers to authorized requests upstream has not marked as shared
+    let ttl = match cache.enabled
+        && response.status() == StatusCode::OK
+        && !varies(response.headers())
+        && (!authorized || allows_authorized_storage(response.headers()))
+    {
+        true => cache.policy.ttl(&path, response.headers()),
+        false => None,
+    };
+    // Without a Content-Length the size is only known at the end, so try and
+    // give up if the body turns out too big
+    let cacheable = ttl.is_some() && content_length.is_none_or(|len| len <= cache.max_object_size() as u64);
+
+    // Too big for memory but within the disk budget: copy to disk while streaming
+    if let (Some(disk), Some(len), Some(ttl)) = (cache.disk.as_ref(), content_length, ttl) {
+        if !cacheable && len <= disk.max_size() {
+            return Ok(tee_to_disk(disk, path, ttl, response).await);
+        }
+    }
+
+    match ttl {
+        Some(ttl) if cacheable => Ok(tee_to_cache(cache.clone(), path, ttl, response)),
+        _ => {
+            if cache.enabled {
+                info!("Not caching response for: {} (status: {}, content-length: {:?}, ttl: {:?})",
+                      path, response.status(), content_length, ttl);
+            }
+            Ok(response)
+        }
+    }
+}
+
+// Refresh an entry that was just served stale. Nobody waits for the result;
+// the response is drained so that it gets stored. A refresh already running
+// for the key is left to finish on its own.
+fn revalidate(cache: &AppCache, flights: &InFlight, path: &str, object: CachedObject, request: &Request<Body>, next: &Next) {
+    if flights.contains(path) {
+        return;
+    }
+    let mut background = Request::new(Body::empty());
+    *background.uri_mut() = request.uri().clone();
+    *background.version_mut() = request.version();
+    *background.headers_mut() = request.headers().clone();
+    *background.extensions_mut() = request.extensions().clone();
+    // The refresh is shared and stored for everyone, so it goes out anonymously
+    for name in TAILORING_HEADERS.iter().chain(CREDENTIAL_HEADERS.iter()) {
+        background.headers_mut().remove(name);
+    }
+
+    let shareable = shareable(cache);
+    let fetch = fetch(cache.clone(), path.to_string(), Some(object), background, next.clone());
+    let (flights, key) = (flights.clone(), path.to_string());
+    tokio::spawn(async move {
+        let response = flights
+            .run(key, async move { fetch.await.unwrap_or_else(IntoResponse::into_response) }, shareable)
+            .await;
+        let mut body = response.into_body().into_data_stream();
+        while let Some(Ok(_)) = body.next().await {}
+    });
+}
+
+// Serve an expired entry, marked so clients can tell
+async fn stale_response(
+    cache: &AppCache,
+    request_headers: &HeaderMap,
+    object: CachedObject,
+    warning: &'static str,
+) -> Result<Response<Body>, AppError> {
+    let mut response = cached_response(request_headers, object).await?;
+    response.headers_mut().insert(X_CACHE.clone(), HeaderValue::from_static("STALE"));
+    response.headers_mut().insert(header::WARNING, HeaderValue::from_static(warning));
+    cache.stats.stale.fetch_add(1, Ordering::Relaxed);
+    Ok(response)
+}
+
+// Whether other clients may read a coalesced fetch's body. Private answers
+// are for the client that started the fetch alone. A body no tier would
+// store gains nothing from sharing, and would tie every client to the pace
+// of the slowest one. Other clients fetch these separately.
+fn shareable(cache: &AppCache) -> impl FnOnce(&Response<Body>) -> bool + Send + 'static {
+    let disk = cache.disk.as_ref().map_or(0, DiskCache::max_size);
+    let limit = (cache.max_object_size() as u64).max(disk);
+    move |response| {
+        let headers = response.headers();
+        !varies(headers) && !is_private(headers) && content_length(headers).is_none_or(|len| len <= limit)
+    }
+}
+
+// Whether the response names request headers other than Accept-Encoding in
+// Vary. The cache key is the URL alone, and Accept-Encoding is stripped from
+// requests that may be stored, so any other header could select a variant
+// meant for a different client.
+fn varies(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::VARY)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("*").split(','))
+        .map(str::trim)
+        .any(|name| !name.is_empty() && !name.eq_ignore_ascii_case(header::ACCEPT_ENCODING.as_str()))
+}
+
+// Whether the request carries credentials upstream may answer differently for
+fn is_credentialed(headers: &HeaderMap) -> bool {
+    CREDENTIAL_HEADERS.iter().any(|name| headers.contains_key(name))
+}
+
+// Whether the response depends on request headers beyond the URL
+fn is_tailored(headers: &HeaderMap) -> bool {
+    TAILORING_HEADERS.iter().any(|name| headers.contains_key(name))
+}
+
+// Pick the headers of an upstream response that are stored with the entry
+fn cacheable_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut kept = HeaderMap::new();
+    for name in CACHED_HEADERS.iter() {
+        for value in headers.get_all(name) {
+            kept.append(name.clone(), value.clone());
+        }
+    }
+    kept
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+// Stream the upstream body to the client and into a disk cache file. The file
+// is only published if the whole body arrived; an upstream error or a client
+// disconnect discards it.
+async fn tee_to_disk(disk: &DiskCache, path: String, ttl: u64, response: Response<Body>) -> Response<Body> {
+    let expected = content_length(response.headers());
+    let writer = match disk
+        .writer(path.clone(), response.status(), cacheable_headers(response.headers()), ttl, expected)
+        .await
+    {
+        Ok(writer) => match lfs_object_id(&path) {
+            Some(oid) => writer.verify_sha256(oid.to_string()),
+            None => writer,
+        },
+        Err(e) => {
+            warn!("Failed to start disk cache write for {}: {}", path, e);
+            return response;
+        }
+    };
+    info!("Caching response on disk for: {}, size: {:?} bytes", path, expected);
+
+    let (parts, body) = response.into_parts();
+    let stream = futures::stream::unfold(
+        (body.into_data_stream(), Some(writer), path),
+        |(mut body, mut writer, path)| async move {
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    if let Some(w) = writer.as_mut() {
+                        if let Err(e) = w.write(&chunk).await {
+                            warn!("Abandoning disk cache write for {}: {}", path, e);
+                            writer = None;
+                        }
+                    }
+                    // The server stops polling once Content-Length bytes are
+                    // out, so commit on the last chunk rather than at the end
+                    if let Some(w) = writer.take_if(|w| w.is_complete()) {
+                        if let Err(e) = w.commit().await {
+                            warn!("Failed to commit {} to disk cache: {}", path, e);
+                        }
+                    }
+                    Some((Ok(chunk), (body, writer, path)))
+                }
+                Some(Err(e)) => Some((Err(e), (body, None, path))),
+                None => {
+                    if let Some(w) = writer.take() {
+                        if let Err(e) = w.commit().await {
+                            warn!("Failed to commit {} to disk cache: {}", path, e);
+                        }
+                    }
+                    None
+                }
+            }
+        },
+    );
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+// A copy of a response body being collected for the cache backend
+struct PendingEntry {
+    cache: AppCache,
+    path: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    ttl: u64,
+    expected: Option<u64>,
+    data: Vec<u8>,
+}
+
+impl PendingEntry {
+    // Store the copy, unless the body ended short of its Content-Length or,
+    // for an LFS object, does not hash to its id
+    fn commit(mut self) {
+        if self.expected.is_some_and(|len| len != self.data.len() as u64) {
+            warn!("Not caching truncated response for {}: got {} of {:?} bytes", self.path, self.data.len(), self.expected);
+            return;
+        }
+        // Bodies of unknown length grow the buffer in steps; trim it so the
+        // memory charge matches what is actually held
+        self.data.shrink_to_fit();
+        let data = Bytes::from(self.data);
+        let (cache, path, status, headers, ttl) = (self.cache, self.path, self.status, self.headers, self.ttl);
+        tokio::spawn(async move {
+            if let Some(oid) = lfs_object_id(&path) {
+                let digest = utils::hex(&Sha256::digest(&data));
+                if digest != oid {
+                    warn!("Not caching {}: body hashes to {}", path, digest);
+                    return;
+                }
+            }
+            info!("Caching response for: {}, size: {} bytes", path, data.len());
+            if let Some(disk) = cache.disk.clone() {
+                let (key, headers, data) = (path.clone(), headers.clone(), data.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = disk.store(key.clone(), status, headers, ttl, &data).await {
+                        warn!("Failed to write {} to disk cache: {}", key, e);
+                    }
+                });
+            }
+            if !cache.set(path.clone(), status, headers, ttl, data).await {
+                info!("Failed to cache response for: {} (likely due to size or memory limits)", path);
+            }
+        });
+    }
+}
 
-    // Cache the response if it's successful and caching is enabled
+// Stream a response to the client while keeping a copy for the cache. The
+// copy is dropped as soon as it outgrows what the backend takes, and only
+// stored once the whole body has gone through.
+fn tee_to_cache(cache: AppCache, path: String, ttl: u64, response: Response<Body>) -> Response<Body> {
+    let limit = cache.max_object_size();
+    let expected = content_length(response.headers());
     let (parts, body) = response.into_parts();
-    let bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| AppError::CacheError(e.to_string()))?;
-    
-    // Only cache successful responses
-    if cache.enabled && parts.status.is_success() {
-        let size_before = cache.get_memory_usage();
-        if cache.set(path.clone(), bytes.to_vec()) {
-            let size_after = cache.get_memory_usage();
-            info!("Caching response for: {}, size: {} bytes, total usage: {} bytes", 
-                  path, bytes.len(), size_after);
-            if size_after > size_before {
-                info!("Cache memory usage increased from {} to {}", size_before, size_after);
+    let pending = PendingEntry {
+        cache,
+        path,
+        status: parts.status,
+        headers: cacheable_headers(&parts.headers),
+        ttl,
+        expected,
+        data: Vec::with_capacity(expected.map_or(0, |len| len as usize)),
+    };
+    let stream = futures::stream::unfold(
+        (body.into_data_stream(), Some(pending)),
+        move |(mut body, mut pending)| async move {
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    if let Some(p) = pending.as_mut() {
+                        if p.data.len() + chunk.len() > limit {
+                            info!("Not caching response for: {} (body exceeds {} bytes)", p.path, limit);
+                            pending = None;
+                        } else {
+                            p.data.extend_from_slice(&chunk);
+                        }
+                    }
+                    // As with disk writes, commit on the last chunk of a body
+                    // with a known length
+                    if let Some(p) = pending.take_if(|p| p.expected == Some(p.data.len() as u64)) {
+                        p.commit();
+                    }
+                    Some((Ok(chunk), (body, pending)))
+                }
+                // A failed or aborted body never reaches the cache
+                Some(Err(e)) => Some((Err(e), (body, None))),
+                None => {
+                    if let Some(p) = pending.take() {
+                        p.commit();
+                    }
+                    None
+                }
             }
-        } else {
-            info!("Failed to cache response for: {} (likely due to size or memory limits)", path);
+        },
+    );
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+// Copy a small disk hit into the cache backend in the background
+fn promote(cache: &AppCache, path: &str, object: &CachedObject, file: &std::path::Path) {
+    let limit = cache.disk.as_ref().map_or(0, DiskCache::promote_max_size);
+    if object.size > limit || object.size > cache.max_object_size() as u64 {
+        return;
+    }
+    let (cache, key, file) = (cache.clone(), path.to_string(), file.to_path_buf());
+    let (status, headers, ttl, timestamp) = (object.status, object.headers.clone(), object.ttl, object.timestamp);
+    tokio::spawn(async move {
+        if let Ok(data) = tokio::fs::read(&file).await {
+            debug!("Promoting {} from disk ({} bytes)", key, data.len());
+            cache.set_with_timestamp(key, status, headers, ttl, data.into(), timestamp).await;
         }
-    } else if cache.enabled {
-        info!("Not caching response for: {} (status: {})", path, parts.status);
+    });
+}
+
+// Build the response for a cache hit, honouring Range and If-Range
+async fn cached_response(request_headers: &HeaderMap, object: CachedObject) -> Result<Response<Body>, AppError> {
+    let total = object.size;
+
+    let etag = object.headers.get(header::ETAG).and_then(|v| v.to_str().ok());
+    let last_modified = object.headers.get(header::LAST_MODIFIED).and_then(|v| v.to_str().ok());
+    let range = request_headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| {
+            // A stale If-Range validator means the client's partial copy is of
+            // another version, so it gets the full representation instead
+            request_headers
+                .get(header::IF_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .is_none_or(|v| utils::if_range_matches(v, etag, last_modified))
+        })
+        .map(|v| utils::parse_range(v, total))
+        .unwrap_or(RangeRequest::Full);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    // The client already holds this version
+    if utils::is_not_modified(request_headers, etag, last_modified) {
+        let mut builder = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::AGE, now.saturating_sub(object.timestamp))
+            .header(X_CACHE.clone(), HeaderValue::from_static("HIT"));
+        for name in [header::ETAG, header::LAST_MODIFIED, header::CACHE_CONTROL, header::EXPIRES, header::VARY] {
+            for value in object.headers.get_all(&name) {
+                builder = builder.header(&name, value);
+            }
+        }
+        return builder.body(Body::empty()).map_err(|e| AppError::CacheError(e.to_string()));
     }
 
-    Ok(Response::from_parts(parts, Body::from(bytes)))
+    let mut builder = Response::builder()
+        .header(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"))
+        .header(header::AGE, now.saturating_sub(object.timestamp))
+        .header(X_CACHE.clone(), HeaderValue::from_static("HIT"));
+    if let Some(headers) = builder.headers_mut() {
+        headers.extend(object.headers);
+        headers
+            .entry(header::CONTENT_TYPE)
+            .or_insert(HeaderValue::from_static("application/octet-stream"));
+    }
+
+    let (builder, start, len) = match range {
+        RangeRequest::Full => (builder.status(object.status), 0, total),
+        RangeRequest::Partial(range) => {
+            debug!("Serving cached range {}", range.content_range(total));
+            let builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, range.content_range(total));
+            (builder, range.start, range.end - range.start + 1)
+        }
+        RangeRequest::Unsatisfiable => {
+            return builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+                .body(Body::empty())
+                .map_err(|e| AppError::CacheError(e.to_string()));
+        }
+    };
+
+    let body = match object.body {
+        CachedBody::Memory(data) => {
+            Body::from(data.slice(start as usize..(start + len) as usize))
+        }
+        CachedBody::Disk(path) => {
+            // Stream the requested span straight from the file
+            let mut file = tokio::fs::File::open(&path).await?;
+            file.seek(SeekFrom::Start(start)).await?;
+            let builder = builder.header(header::CONTENT_LENGTH, len);
+            return builder
+                .body(Body::from_stream(ReaderStream::new(file.take(len))))
+                .map_err(|e| AppError::CacheError(e.to_string()));
+        }
+    };
+
+    builder.body(body).map_err(|e| AppError::CacheError(e.to_string()))
 }
 
 // Helper function to extract request path
@@ -81,10 +597,25 @@ fn get_request_path(request: &Request<Body>) -> String {
         .map(|v| v.as_str())
         .unwrap_or(request.uri().path());
 
-    let path = if path_query.starts_with("/") {
-        path_query[1..].into()
-    } else {
-        path_query
-    };
-    path.into()
-}
\ No newline at end of file
+    path_query.strip_prefix('/').unwrap_or(path_query).into()
+}
+
+// Key responses by the upstream URL the handler will actually fetch, so
+// equivalent spellings share one entry. http and https are the same object.
+// LFS objects are content-addressed and their storage URLs carry short-lived
+// signatures, so they are keyed by OID.
+pub fn cache_key(path: &str, config: &Config) -> String {
+    let url = utils::process_url(utils::normalize_url(path), config);
+    match utils::lfs_oid(&url) {
+        Some(oid) => format!("{}{}", LFS_KEY_PREFIX, oid),
+        None => match url.strip_prefix("http://") {
+            Some(rest) => format!("https://{}", rest),
+            None => url,
+        },
+    }
+}
+
+// Object id of an LFS object's cache key
+pub fn lfs_object_id(key: &str) -> Option<&str> {
+    key.strip_prefix(LFS_KEY_PREFIX)
+}
@@ -0,0 +1,543 @@
+use axum::{
+    body::Body,
+    http::{HeaderMap, Response, StatusCode},
+};
+use bytes::Bytes;
+use futures::{Future, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::watch;
+use tracing::debug;
+
+// How far clients may fall behind. Once even the fastest client has this many
+// bytes left to read, the upstream read pauses until it catches up; a client
+// this far behind the fastest one is cut off, so one stalled connection
+// cannot hold up everyone else.
+const BUFFER_LIMIT: usize = 1 << 20;
+
+// Upstream fetches currently running, by cache key. Concurrent misses for the
+// same key join the running fetch instead of starting their own.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+}
+
+// One shared upstream fetch. Chunks are kept until every client has read
+// them; the opening ones stay while they fit the buffer, so clients arriving
+// a little late can still replay the body from the start.
+struct Flight {
+    state: Mutex<FlightState>,
+    // Signalled when the head or a chunk arrives, or the body ends
+    changed: watch::Sender<()>,
+    // Signalled when a client reads a chunk or leaves
+    drained: watch::Sender<()>,
+}
+
+#[derive(Default)]
+struct FlightState {
+    head: Option<Head>,
+    chunks: VecDeque<Bytes>,
+    // Sequence number of the first chunk still held
+    base: usize,
+    buffered: usize,
+    // Body bytes read from upstream so far
+    received: usize,
+    // Clients still reading; one cut off for falling behind has none
+    cursors: HashMap<u64, Cursor>,
+    next_waiter: u64,
+    // Set once the body is complete, or with the reason it failed
+    end: Option<Result<(), String>>,
+}
+
+// Where a client is in the body
+#[derive(Clone, Copy)]
+struct Cursor {
+    // Sequence number of the next chunk to read
+    chunk: usize,
+    // Body bytes read so far
+    offset: usize,
+}
+
+#[derive(Clone)]
+struct Head {
+    status: StatusCode,
+    headers: HeaderMap,
+    // Whether clients other than the one that started the fetch may read it
+    shared: bool,
+}
+
+// A client reading from a flight
+struct Waiter {
+    flight: Arc<Flight>,
+    id: u64,
+}
+
+// What a client finds when it looks for its next chunk
+enum Next {
+    Chunk(Bytes),
+    Done,
+    Failed(String),
+    Wait,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Run `fetch` unless a fetch for `key` is already in flight, and stream its
+    // response. The fetch runs in its own task: it keeps going when the client
+    // that started it disconnects, and stops once no client is left. Once the
+    // response head is in, `shareable` decides whether other clients may read
+    // the body; those that may not, or that come too late to read it from the
+    // start, run their own `fetch` instead.
+    pub async fn run<F, S>(&self, key: String, fetch: F, shareable: S) -> Response<Body>
+    where
+        F: Future<Output = Response<Body>> + Send + 'static,
+        S: FnOnce(&Response<Body>) -> bool + Send + 'static,
+    {
+        let joined = {
+            let mut flights = self.lock();
+            match flights.get(&key) {
+                Some(flight) if flight.lock().base == 0 => {
+                    debug!("Joining in-flight fetch for: {}", key);
+                    Some((Waiter::new(flight), false))
+                }
+                Some(_) => None,
+                None => {
+                    let flight = Arc::new(Flight {
+                        state: Mutex::new(FlightState::default()),
+                        changed: watch::Sender::new(()),
+                        drained: watch::Sender::new(()),
+                    });
+                    flights.insert(key.clone(), flight.clone());
+                    Some((Waiter::new(&flight), true))
+                }
+            }
+        };
+        let Some((waiter, leader)) = joined else {
+            debug!("In-flight fetch for {} is past its start, fetching separately", key);
+            return fetch.await;
+        };
+
+        if leader {
+            let flights = self.clone();
+            let flight = waiter.flight.clone();
+            tokio::spawn(async move { flights.drive(key, flight, fetch, shareable).await });
+            let head = waiter.head().await;
+            return respond(waiter, head);
+        }
+
+        let head = waiter.head().await;
+        if !head.shared {
+            drop(waiter);
+            debug!("In-flight fetch for {} is not shared, fetching separately", key);
+            return fetch.await;
+        }
+        respond(waiter, head)
+    }
+
+    // Whether a fetch for `key` is running
+    pub fn contains(&self, key: &str) -> bool {
+        self.lock().contains_key(key)
+    }
+
+    async fn drive<F, S>(&self, key: String, flight: Arc<Flight>, fetch: F, shareable: S)
+    where
+        F: Future<Output = Response<Body>>,
+        S: FnOnce(&Response<Body>) -> bool,
+    {
+        let response = fetch.await;
+        let shared = shareable(&response);
+        if !shared {
+            // Nobody else may join from here on
+            self.forget(&key, &flight);
+        }
+        let (parts, body) = response.into_parts();
+        flight.update(|state| {
+            state.head = Some(Head {
+                status: parts.status,
+                headers: parts.headers,
+                shared,
+            })
+        });
+
+        let mut drained = flight.drained.subscribe();
+        let mut body = body.into_data_stream();
+        let end = 'read: loop {
+            // Read on only once the fastest client is close enough to keep up
+            loop {
+                drained.borrow_and_update();
+                if self.abandon(&key, &flight) {
+                    debug!("All clients left, cancelling fetch for: {}", key);
+                    break 'read Err("cancelled".to_string());
+                }
+                if flight.lock().fastest_lag().is_none_or(|lag| lag < BUFFER_LIMIT) {
+                    break;
+                }
+                let _ = drained.changed().await;
+            }
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    let detached = flight.update(|state| state.push(chunk));
+                    if detached > 0 {
+                        debug!("Cut off {} clients that fell behind on: {}", detached, key);
+                    }
+                }
+                Some(Err(e)) => break Err(e.to_string()),
+                None => break Ok(()),
+            }
+        };
+
+        self.forget(&key, &flight);
+        flight.update(|state| state.end = Some(end));
+    }
+
+    // Drop the flight if nobody is waiting for it. Joining happens under the
+    // same lock, so a client cannot join a flight that is being cancelled.
+    fn abandon(&self, key: &str, flight: &Arc<Flight>) -> bool {
+        let mut flights = self.lock();
+        if !flight.lock().cursors.is_empty() {
+            return false;
+        }
+        if flights.get(key).is_some_and(|current| Arc::ptr_eq(current, flight)) {
+            flights.remove(key);
+        }
+        true
+    }
+
+    fn forget(&self, key: &str, flight: &Arc<Flight>) {
+        let mut flights = self.lock();
+        if flights.get(key).is_some_and(|current| Arc::ptr_eq(current, flight)) {
+            flights.remove(key);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Arc<Flight>>> {
+        match self.flights.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Flight {
+    fn update<R>(&self, f: impl FnOnce(&mut FlightState) -> R) -> R {
+        let result = f(&mut self.lock());
+        self.changed.send_replace(());
+        result
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FlightState> {
+        match self.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl FlightState {
+    // Add a chunk, cutting off clients now too far behind. Returns how many were.
+    fn push(&mut self, chunk: Bytes) -> usize {
+        self.buffered += chunk.len();
+        self.received += chunk.len();
+        self.chunks.push_back(chunk);
+        let detached = self.detach_laggards();
+        self.release();
+        detached
+    }
+
+    // Bytes the fastest client has yet to read
+    fn fastest_lag(&self) -> Option<usize> {
+        self.cursors.values().map(|cursor| self.received - cursor.offset).min()
+    }
+
+    fn detach_laggards(&mut self) -> usize {
+        let Some(fastest) = self.fastest_lag() else { return 0 };
+        let received = self.received;
+        let laggards: Vec<u64> = self
+            .cursors
+            .iter()
+            .filter(|(_, cursor)| received - cursor.offset - fastest > BUFFER_LIMIT)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in &laggards {
+            self.cursors.remove(id);
+        }
+        laggards.len()
+    }
+
+    // Drop chunks every client has read. The opening chunks are kept while
+    // they fit the buffer; once one is gone, nobody new can join anyway.
+    fn release(&mut self) {
+        let slowest = self
+            .cursors
+            .values()
+            .map(|cursor| cursor.chunk)
+            .min()
+            .unwrap_or(self.base + self.chunks.len());
+        while self.base < slowest && (self.base > 0 || self.buffered >= BUFFER_LIMIT) {
+            let Some(chunk) = self.chunks.pop_front() else { break };
+            self.buffered -= chunk.len();
+            self.base += 1;
+        }
+    }
+}
+
+impl Waiter {
+    // Start reading at the oldest chunk held
+    fn new(flight: &Arc<Flight>) -> Self {
+        let mut state = flight.lock();
+        let id = state.next_waiter;
+        state.next_waiter += 1;
+        let cursor = Cursor {
+            chunk: state.base,
+            offset: state.received - state.buffered,
+        };
+        state.cursors.insert(id, cursor);
+        Self {
+            flight: flight.clone(),
+            id,
+        }
+    }
+
+    // Wait for the shared response head
+    async fn head(&self) -> Head {
+        let mut changed = self.flight.changed.subscribe();
+        loop {
+            changed.borrow_and_update();
+            if let Some(head) = self.flight.lock().head.clone() {
+                return head;
+            }
+            // The sender lives in the flight we hold, so this cannot fail
+            let _ = changed.changed().await;
+        }
+    }
+
+    fn next(&self) -> Next {
+        let mut state = self.flight.lock();
+        let Some(cursor) = state.cursors.get(&self.id).copied() else {
+            return Next::Failed("fell too far behind the shared download".to_string());
+        };
+        if let Some(chunk) = state.chunks.get(cursor.chunk - state.base).cloned() {
+            let next = Cursor {
+                chunk: cursor.chunk + 1,
+                offset: cursor.offset + chunk.len(),
+            };
+            state.cursors.insert(self.id, next);
+            state.release();
+            drop(state);
+            self.flight.drained.send_replace(());
+            return Next::Chunk(chunk);
+        }
+        match &state.end {
+            Some(Ok(())) => Next::Done,
+            Some(Err(e)) => Next::Failed(e.clone()),
+            None => Next::Wait,
+        }
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let mut state = self.flight.lock();
+        state.cursors.remove(&self.id);
+        state.release();
+        drop(state);
+        self.flight.drained.send_replace(());
+    }
+}
+
+// Stream the body as it arrives
+fn respond(waiter: Waiter, head: Head) -> Response<Body> {
+    let changed = waiter.flight.changed.subscribe();
+    let stream = futures::stream::unfold(Some((waiter, changed)), |state| async move {
+        let (waiter, mut changed) = state?;
+        loop {
+            changed.borrow_and_update();
+            match waiter.next() {
+                Next::Chunk(chunk) => return Some((Ok(chunk), Some((waiter, changed)))),
+                Next::Done => return None,
+                Next::Failed(e) => return Some((Err(std::io::Error::other(e)), None)),
+                Next::Wait => {
+                    let _ = changed.changed().await;
+                }
+            }
+        }
+    });
+
+    let mut response = Response::new(Body::from_stream(stream));
+    *response.status_mut() = head.status;
+    *response.headers_mut() = head.headers;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    type Chunks = mpsc::UnboundedSender<std::io::Result<Bytes>>;
+
+    // A fetch whose body is fed by hand, counting how often fetches start
+    // and how many chunks have been read from it
+    fn upstream(fetches: &Arc<AtomicUsize>) -> (Chunks, impl Future<Output = Response<Body>> + Send + 'static, Arc<AtomicUsize>) {
+        let (sender, receiver) = mpsc::unbounded();
+        let (fetches, read) = (fetches.clone(), Arc::new(AtomicUsize::new(0)));
+        let counter = read.clone();
+        let fetch = async move {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            let body = receiver.inspect(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+            Response::new(Body::from_stream(body))
+        };
+        (sender, fetch, read)
+    }
+
+    // A fetch that answers at once with `body`
+    fn answer(fetches: &Arc<AtomicUsize>, body: &'static str) -> impl Future<Output = Response<Body>> + Send + 'static {
+        let fetches = fetches.clone();
+        async move {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            Response::new(Body::from(body))
+        }
+    }
+
+    fn shared(_: &Response<Body>) -> bool {
+        true
+    }
+
+    async fn read_all(response: Response<Body>) -> std::result::Result<Vec<u8>, String> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .map(|bytes| bytes.to_vec())
+            .map_err(|e| e.to_string())
+    }
+
+    fn chunk(byte: u8, len: usize) -> std::io::Result<Bytes> {
+        Ok(Bytes::from(vec![byte; len]))
+    }
+
+    #[tokio::test]
+    async fn upstream_error_reaches_every_waiter() {
+        let flights = InFlight::new();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let (chunks, fetch, _) = upstream(&fetches);
+
+        let first = flights.run("key".to_string(), fetch, shared).await;
+        let second = flights.run("key".to_string(), answer(&fetches, "separate"), shared).await;
+        chunks.unbounded_send(chunk(b'a', 10)).unwrap();
+        chunks.unbounded_send(Err(std::io::Error::other("connection reset"))).unwrap();
+
+        let (first, second) = tokio::join!(read_all(first), read_all(second));
+        assert!(first.is_err());
+        assert!(second.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(!flights.contains("key"));
+    }
+
+    #[tokio::test]
+    async fn disconnecting_waiter_leaves_the_others_reading() {
+        let flights = InFlight::new();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let (chunks, fetch, _) = upstream(&fetches);
+
+        let leader = flights.run("key".to_string(), fetch, shared).await;
+        let follower = flights.run("key".to_string(), answer(&fetches, "separate"), shared).await;
+        chunks.unbounded_send(chunk(b'a', 10)).unwrap();
+        // The client that started the fetch goes away
+        drop(leader);
+        chunks.unbounded_send(chunk(b'b', 10)).unwrap();
+        drop(chunks);
+
+        let body = read_all(follower).await.unwrap();
+        assert_eq!(body, [[b'a'; 10], [b'b'; 10]].concat());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn joining_is_refused_once_the_opening_bytes_are_gone() {
+        let flights = InFlight::new();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let (chunks, fetch, _) = upstream(&fetches);
+
+        let leader = flights.run("key".to_string(), fetch, shared).await;
+        let mut leader = leader.into_body().into_data_stream();
+        chunks.unbounded_send(chunk(b'a', BUFFER_LIMIT / 2)).unwrap();
+        assert_eq!(leader.next().await.unwrap().unwrap().len(), BUFFER_LIMIT / 2);
+
+        // Bytes sent, but still held: a late client replays them from the start
+        let early = flights.run("key".to_string(), answer(&fetches, "separate"), shared).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // Past the buffer, chunks everyone has read are released
+        chunks.unbounded_send(chunk(b'b', BUFFER_LIMIT / 2)).unwrap();
+        assert_eq!(leader.next().await.unwrap().unwrap().len(), BUFFER_LIMIT / 2);
+        let early = tokio::spawn(read_all(early));
+        chunks.unbounded_send(chunk(b'c', 10)).unwrap();
+        assert_eq!(leader.next().await.unwrap().unwrap().len(), 10);
+        while flights.lock().get("key").is_some_and(|flight| flight.lock().base == 0) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let late = flights.run("key".to_string(), answer(&fetches, "separate"), shared).await;
+        assert_eq!(read_all(late).await.unwrap(), b"separate");
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        drop(chunks);
+        assert!(leader.next().await.is_none());
+        let early = early.await.unwrap().unwrap();
+        assert_eq!(early.len(), BUFFER_LIMIT + 10);
+        assert_eq!(early[0], b'a');
+    }
+
+    #[tokio::test]
+    async fn stalled_waiter_is_cut_off_instead_of_stalling_the_rest() {
+        let flights = InFlight::new();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let (chunks, fetch, _) = upstream(&fetches);
+
+        let reader = flights.run("key".to_string(), fetch, shared).await;
+        // Joins and never reads
+        let stalled = flights.run("key".to_string(), answer(&fetches, "separate"), shared).await;
+
+        let chunk_len = 64 * 1024;
+        let total = 4 * BUFFER_LIMIT;
+        for _ in 0..total / chunk_len {
+            chunks.unbounded_send(chunk(b'a', chunk_len)).unwrap();
+        }
+        drop(chunks);
+
+        let body = tokio::time::timeout(Duration::from_secs(10), read_all(reader)).await.unwrap().unwrap();
+        assert_eq!(body.len(), total);
+        assert!(read_all(stalled).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn upstream_reads_pause_when_every_waiter_falls_behind() {
+        let flights = InFlight::new();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let (chunks, fetch, read) = upstream(&fetches);
+
+        let first = flights.run("key".to_string(), fetch, shared).await;
+        let second = flights.run("key".to_string(), answer(&fetches, "separate"), shared).await;
+
+        let chunk_len = 64 * 1024;
+        let count = 4 * BUFFER_LIMIT / chunk_len;
+        for _ in 0..count {
+            chunks.unbounded_send(chunk(b'a', chunk_len)).unwrap();
+        }
+        drop(chunks);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // Reading stopped at the buffer, with nobody cut off
+        assert_eq!(read.load(Ordering::SeqCst), BUFFER_LIMIT / chunk_len);
+
+        let (first, second) = tokio::join!(read_all(first), read_all(second));
+        assert_eq!(first.unwrap().len(), count * chunk_len);
+        assert_eq!(second.unwrap().len(), count * chunk_len);
+        assert_eq!(read.load(Ordering::SeqCst), count);
+    }
+}
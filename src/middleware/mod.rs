@@ -1,5 +1,9 @@
+pub mod admin;
 pub mod rate_limit;
 pub mod cache;
+pub mod coalesce;
 
-pub use rate_limit::{rate_limit_middleware, RateLimiter};
-pub use cache::cache_middleware;
\ No newline at end of file
+pub use admin::admin_auth_middleware;
+pub use rate_limit::{rate_limit_middleware, RateLimiter, SystemClock};
+pub use cache::{cache_key, cache_middleware, lfs_object_id};
+pub use coalesce::InFlight;
\ No newline at end of file
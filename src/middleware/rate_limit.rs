@@ -1,38 +1,99 @@
 use axum::{
-    extract::State,
-    http::{HeaderMap, Request},
+    extract::{ConnectInfo, State},
+    http::Request,
     middleware::Next,
     response::Response,
 };
-use std::collections::HashMap;
+use ipnet::IpNet;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
-use tracing::{info, warn};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
-use crate::error::AppError;
+use crate::config::{RateLimitAlgorithm, RateLimitConfig};
+use crate::error::{AppError, Result};
+use crate::utils::{self, TrustedProxies};
 
-// Rate limit entry
-#[derive(Clone)]
-pub struct RateLimitEntry {
-    pub count: u32,
-    pub timestamp: u64,
+// Span of the sliding window and of requests_per_minute
+const WINDOW: Duration = Duration::from_secs(60);
+
+// Source of the current time, replaceable so limits can be exercised without waiting
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Allowance of one client
+#[derive(Clone, Copy)]
+struct Limit {
+    requests_per_minute: u32,
+    burst: u32,
+}
+
+impl Limit {
+    fn new(requests_per_minute: u32, burst: Option<u32>) -> Self {
+        Self {
+            requests_per_minute,
+            burst: burst.unwrap_or(requests_per_minute),
+        }
+    }
+}
+
+// Rate limit state of one client
+enum ClientState {
+    // Tokens left as of `updated`
+    Bucket { tokens: f64, updated: Instant },
+    // Times of the requests made in the last window, oldest first
+    Log(VecDeque<Instant>),
+}
+
+struct Clients {
+    states: HashMap<String, ClientState>,
+    // Last time idle clients were dropped
+    swept: Instant,
 }
 
 // Rate limiter
 #[derive(Clone)]
 pub struct RateLimiter {
-    pub limits: Arc<RwLock<HashMap<String, RateLimitEntry>>>,
+    limits: Arc<RwLock<Clients>>,
     pub enabled: bool,
-    pub requests_per_minute: u32,
+    algorithm: RateLimitAlgorithm,
+    limit: Limit,
+    // Clients with a limit of their own, checked in order
+    overrides: Arc<[(Vec<IpNet>, Limit)]>,
+    clock: Arc<dyn Clock>,
 }
 
 impl RateLimiter {
-    pub fn new(enabled: bool, requests_per_minute: u32) -> Self {
-        Self {
-            limits: Arc::new(RwLock::new(HashMap::new())),
-            enabled,
-            requests_per_minute,
-        }
+    pub fn new(config: &RateLimitConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        let overrides = config
+            .overrides
+            .iter()
+            .map(|entry| {
+                let networks = utils::parse_networks(&entry.clients, "rate limit client")?;
+                Ok((networks, Limit::new(entry.requests_per_minute, entry.burst)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            limits: Arc::new(RwLock::new(Clients {
+                states: HashMap::new(),
+                swept: clock.now(),
+            })),
+            enabled: config.enabled,
+            algorithm: config.algorithm,
+            limit: Limit::new(config.requests_per_minute, config.burst),
+            overrides: overrides.into(),
+            clock,
+        })
     }
 
     pub fn is_allowed(&self, key: &str) -> bool {
@@ -40,69 +101,105 @@ impl RateLimiter {
             return true;
         }
 
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_else(|_| std::time::Duration::from_secs(0))
-            .as_secs();
-
+        let limit = self.limit_for(key);
+        let now = self.clock.now();
         let mut limits = match self.limits.write() {
             Ok(guard) => guard,
             Err(poisoned) => poisoned.into_inner(),
         };
-        
-        let entry = limits.entry(key.to_string()).or_insert_with(|| RateLimitEntry {
-            count: 0,
-            timestamp: current_time,
-        });
-
-        // Reset counter if more than a minute has passed
-        if current_time - entry.timestamp >= 60 {
-            info!("Resetting rate limit counter for: {}", key);
-            entry.count = 1;
-            entry.timestamp = current_time;
-            return true;
+
+        // Forget clients that are back at their full allowance, so the map
+        // only holds those seen recently
+        if now.duration_since(limits.swept) >= WINDOW {
+            let before = limits.states.len();
+            limits.states.retain(|key, state| !self.is_idle(state, self.limit_for(key), now));
+            limits.swept = now;
+            debug!("Dropped rate limit state of {} idle clients", before - limits.states.len());
         }
 
-        // Check if limit is exceeded
-        if entry.count >= self.requests_per_minute {
+        let state = limits
+            .states
+            .entry(key.to_string())
+            .or_insert_with(|| match self.algorithm {
+                RateLimitAlgorithm::TokenBucket => ClientState::Bucket {
+                    tokens: f64::from(limit.burst),
+                    updated: now,
+                },
+                RateLimitAlgorithm::SlidingWindow => ClientState::Log(VecDeque::new()),
+            });
+
+        let allowed = match state {
+            ClientState::Bucket { tokens, updated } => {
+                *tokens = refill(*tokens, *updated, now, limit);
+                *updated = now;
+                let allowed = *tokens >= 1.0;
+                if allowed {
+                    *tokens -= 1.0;
+                }
+                debug!("Rate limit bucket for {}: {:.2}/{} tokens left", key, tokens, limit.burst);
+                allowed
+            }
+            ClientState::Log(times) => {
+                while times.front().is_some_and(|&time| now.duration_since(time) >= WINDOW) {
+                    times.pop_front();
+                }
+                let allowed = times.len() < limit.requests_per_minute as usize;
+                if allowed {
+                    times.push_back(now);
+                }
+                debug!("Rate limit window for {}: {}/{} requests", key, times.len(), limit.requests_per_minute);
+                allowed
+            }
+        };
+
+        if !allowed {
             warn!("Rate limit exceeded for: {}", key);
-            return false;
         }
+        allowed
+    }
 
-        // Increment count
-        entry.count += 1;
-        info!("Incrementing rate limit counter for: {} (current: {}/{})", key, entry.count, self.requests_per_minute);
-        true
+    // The first override listing the client's address, else the configured limit
+    fn limit_for(&self, key: &str) -> Limit {
+        let Ok(ip) = key.parse::<IpAddr>() else {
+            return self.limit;
+        };
+        let ip = ip.to_canonical();
+        self.overrides
+            .iter()
+            .find(|(networks, _)| networks.iter().any(|network| network.contains(&ip)))
+            .map_or(self.limit, |&(_, limit)| limit)
+    }
+
+    // Whether a client's state is the same as if it had never been seen
+    fn is_idle(&self, state: &ClientState, limit: Limit, now: Instant) -> bool {
+        match state {
+            ClientState::Bucket { tokens, updated } => refill(*tokens, *updated, now, limit) >= f64::from(limit.burst),
+            ClientState::Log(times) => times.back().is_none_or(|&time| now.duration_since(time) >= WINDOW),
+        }
     }
 }
 
-// Get client IP from request headers
-pub fn get_client_ip(headers: &HeaderMap) -> String {
-    headers
-        .get("x-forwarded-for")
-        .and_then(|hv| hv.to_str().ok())
-        .or_else(|| {
-            headers
-                .get("x-real-ip")
-                .and_then(|hv| hv.to_str().ok())
-        })
-        .unwrap_or("unknown")
-        .to_string()
+// Tokens in a bucket that held `tokens` at `updated`
+fn refill(tokens: f64, updated: Instant, now: Instant, limit: Limit) -> f64 {
+    let rate = f64::from(limit.requests_per_minute) / WINDOW.as_secs_f64();
+    (tokens + now.duration_since(updated).as_secs_f64() * rate).min(f64::from(limit.burst))
 }
 
 // Rate limiting middleware
 pub async fn rate_limit_middleware(
-    State(rate_limiter): State<RateLimiter>,
+    State((rate_limiter, trusted_proxies)): State<(RateLimiter, TrustedProxies)>,
     request: Request<axum::body::Body>,
     next: Next,
-) -> Result<Response, AppError> {
+) -> Result<Response> {
     if !rate_limiter.enabled {
         info!("Rate limiting disabled, proceeding with request");
         return Ok(next.run(request).await);
     }
 
-    let client_ip = get_client_ip(request.headers());
-    
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+    let client_ip = utils::client_ip(request.headers(), peer, &trusted_proxies)
+        .map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+
     if !rate_limiter.is_allowed(&client_ip) {
         warn!("Request from {} blocked due to rate limit", client_ip);
         return Err(AppError::RateLimitError("Rate limit exceeded".to_string()));
@@ -110,4 +207,201 @@ pub async fn rate_limit_middleware(
 
     info!("Request from {} passed rate limit check", client_ip);
     Ok(next.run(request).await)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitOverride;
+    use std::sync::Mutex;
+
+    // A clock that only moves when told to
+    struct ManualClock(Mutex<Instant>);
+
+    impl ManualClock {
+        fn new() -> Arc<Self> {
+            Arc::new(Self(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn limiter(algorithm: RateLimitAlgorithm, requests_per_minute: u32, burst: Option<u32>, clock: Arc<ManualClock>) -> RateLimiter {
+        let config = RateLimitConfig {
+            enabled: true,
+            algorithm,
+            requests_per_minute,
+            burst,
+            overrides: Vec::new(),
+        };
+        RateLimiter::new(&config, clock).unwrap()
+    }
+
+    fn with_overrides(overrides: &[(&[&str], u32, Option<u32>)], clock: Arc<ManualClock>) -> Result<RateLimiter> {
+        let config = RateLimitConfig {
+            enabled: true,
+            algorithm: RateLimitAlgorithm::TokenBucket,
+            requests_per_minute: 60,
+            burst: Some(5),
+            overrides: overrides
+                .iter()
+                .map(|&(clients, requests_per_minute, burst)| RateLimitOverride {
+                    clients: clients.iter().map(|client| client.to_string()).collect(),
+                    requests_per_minute,
+                    burst,
+                })
+                .collect(),
+        };
+        RateLimiter::new(&config, clock)
+    }
+
+    fn allowed(limiter: &RateLimiter, key: &str, attempts: u32) -> u32 {
+        (0..attempts).filter(|_| limiter.is_allowed(key)).count() as u32
+    }
+
+    #[test]
+    fn token_bucket_drains_to_burst_and_refills_at_rate() {
+        let clock = ManualClock::new();
+        // One token a second, up to five at once
+        let limiter = limiter(RateLimitAlgorithm::TokenBucket, 60, Some(5), clock.clone());
+
+        assert_eq!(allowed(&limiter, "client", 10), 5);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(allowed(&limiter, "client", 10), 1);
+
+        clock.advance(Duration::from_millis(2500));
+        assert_eq!(allowed(&limiter, "client", 10), 2);
+
+        // A long pause refills no further than the burst
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(allowed(&limiter, "client", 10), 5);
+
+        // Clients have separate buckets
+        assert_eq!(allowed(&limiter, "other", 10), 5);
+    }
+
+    #[test]
+    fn sliding_window_holds_across_minute_boundary() {
+        let clock = ManualClock::new();
+        let limiter = limiter(RateLimitAlgorithm::SlidingWindow, 10, None, clock.clone());
+
+        // The whole allowance at the end of one minute...
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(allowed(&limiter, "client", 20), 10);
+
+        // ...leaves nothing at the start of the next, where a fixed window
+        // would have allowed another 10
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(allowed(&limiter, "client", 20), 0);
+
+        clock.advance(Duration::from_millis(57_900));
+        assert_eq!(allowed(&limiter, "client", 20), 0);
+
+        // A minute after the burst it has all expired
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(allowed(&limiter, "client", 20), 10);
+    }
+
+    #[test]
+    fn idle_clients_are_swept() {
+        let clock = ManualClock::new();
+        let limiter = limiter(RateLimitAlgorithm::SlidingWindow, 10, None, clock.clone());
+
+        assert!(limiter.is_allowed("early"));
+        clock.advance(Duration::from_secs(30));
+        assert!(limiter.is_allowed("recent"));
+
+        // The first request past a window sweeps: "early" made its last
+        // request a full window ago, "recent" is still counted
+        clock.advance(Duration::from_secs(30));
+        assert!(limiter.is_allowed("new"));
+
+        let limits = limiter.limits.read().unwrap();
+        let mut clients: Vec<&str> = limits.states.keys().map(String::as_str).collect();
+        clients.sort_unstable();
+        assert_eq!(clients, ["new", "recent"]);
+    }
+
+    #[test]
+    fn refilled_buckets_are_swept() {
+        let clock = ManualClock::new();
+        let limiter = limiter(RateLimitAlgorithm::TokenBucket, 60, Some(5), clock.clone());
+
+        assert_eq!(allowed(&limiter, "drained", 5), 5);
+        clock.advance(Duration::from_secs(58));
+        assert_eq!(allowed(&limiter, "recent", 5), 5);
+
+        // A minute has refilled "drained"; "recent" is still short
+        clock.advance(Duration::from_millis(2500));
+        assert!(limiter.is_allowed("new"));
+
+        let limits = limiter.limits.read().unwrap();
+        let mut clients: Vec<&str> = limits.states.keys().map(String::as_str).collect();
+        clients.sort_unstable();
+        assert_eq!(clients, ["new", "recent"]);
+    }
+
+    #[test]
+    fn overrides_apply_to_listed_clients() {
+        let clock = ManualClock::new();
+        let limiter = with_overrides(
+            &[
+                (&["10.0.0.0/8", "192.0.2.7"], 600, Some(20)),
+                // Shadowed by the block above
+                (&["10.1.2.3"], 0, None),
+                (&["203.0.113.0/24"], 0, None),
+            ],
+            clock.clone(),
+        )
+        .unwrap();
+
+        assert_eq!(allowed(&limiter, "10.1.2.3", 30), 20);
+        assert_eq!(allowed(&limiter, "192.0.2.7", 30), 20);
+        assert_eq!(allowed(&limiter, "::ffff:10.9.9.9", 30), 20);
+        assert_eq!(allowed(&limiter, "203.0.113.9", 30), 0);
+        // Everyone else gets the configured limit
+        assert_eq!(allowed(&limiter, "192.0.2.8", 30), 5);
+        assert_eq!(allowed(&limiter, "unknown", 30), 5);
+
+        // Each refills at its own rate
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(allowed(&limiter, "10.1.2.3", 30), 10);
+        assert_eq!(allowed(&limiter, "192.0.2.8", 30), 1);
+        assert_eq!(allowed(&limiter, "203.0.113.9", 30), 0);
+    }
+
+    #[test]
+    fn sweeping_uses_each_clients_own_limit() {
+        let clock = ManualClock::new();
+        // Twice a minute's worth of burst, so a minute does not refill it
+        let limiter = with_overrides(&[(&["192.0.2.7"], 6, Some(12))], clock.clone()).unwrap();
+
+        assert_eq!(allowed(&limiter, "192.0.2.7", 12), 12);
+        assert_eq!(allowed(&limiter, "192.0.2.8", 5), 5);
+        clock.advance(Duration::from_millis(60_500));
+        assert!(limiter.is_allowed("new"));
+
+        {
+            let limits = limiter.limits.read().unwrap();
+            let mut clients: Vec<&str> = limits.states.keys().map(String::as_str).collect();
+            clients.sort_unstable();
+            assert_eq!(clients, ["192.0.2.7", "new"]);
+        }
+        assert_eq!(allowed(&limiter, "192.0.2.7", 12), 6);
+    }
+
+    #[test]
+    fn invalid_override_clients_are_rejected() {
+        assert!(with_overrides(&[(&["10.0.0.0/33"], 60, None)], ManualClock::new()).is_err());
+        assert!(with_overrides(&[(&["example.com"], 60, None)], ManualClock::new()).is_err());
+    }
+}
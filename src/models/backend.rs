@@ -0,0 +1,47 @@
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use serde::Serialize;
+
+use super::{CacheEntry, EntrySummary};
+use crate::error::Result;
+
+// Where cached objects are kept. `AppCache` decides what is stored and for
+// how long; a backend only holds entries and hands them back.
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    // Look up an entry, counting the access
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>>;
+
+    // Store an entry that is worth keeping for `retain` more seconds. Returns
+    // false if the backend declined it, e.g. for lack of space.
+    async fn set(&self, key: String, entry: CacheEntry, retain: u64) -> Result<bool>;
+
+    // Take over the result of a successful revalidation
+    async fn refresh(&self, key: &str, headers: &HeaderMap, timestamp: u64, ttl: u64, retain: u64) -> Result<()>;
+
+    async fn remove(&self, key: &str) -> Result<bool>;
+
+    // Remove every entry whose key matches, returning the removed keys
+    async fn remove_matching(&self, predicate: &(dyn for<'k> Fn(&'k str) -> bool + Sync)) -> Result<Vec<String>>;
+
+    // Drop entries that are no longer of any use. Backends that expire
+    // entries on their own have nothing to do here.
+    async fn sweep(&self, is_dead: &(dyn for<'e> Fn(&'e CacheEntry) -> bool + Sync)) -> Result<usize>;
+
+    async fn entries(&self) -> Result<Vec<EntrySummary>>;
+
+    // Largest body the backend accepts
+    fn max_object_size(&self) -> usize;
+
+    fn usage(&self) -> BackendUsage;
+}
+
+// What a backend holds, as far as it can tell cheaply
+#[derive(Serialize)]
+pub struct BackendUsage {
+    pub kind: &'static str,
+    pub entries: Option<usize>,
+    pub max_entries: Option<usize>,
+    pub bytes: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
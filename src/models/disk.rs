@@ -0,0 +1,616 @@
+use axum::http::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+use super::LruMap;
+use crate::utils;
+
+// An object stored on disk. The body stays in `<hash>-<nonce>.body`; only
+// this metadata is kept in memory.
+#[derive(Clone)]
+pub struct DiskEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub timestamp: u64,
+    pub ttl: u64,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    // Hits since the process started; not persisted
+    pub hits: u64,
+}
+
+// Sidecar metadata written next to each body as `<hash>-<nonce>.meta`
+#[derive(Serialize, Deserialize)]
+struct DiskMeta {
+    key: String,
+    size: u64,
+    timestamp: u64,
+    #[serde(default)]
+    ttl: Option<u64>,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+struct DiskIndex {
+    entries: LruMap<DiskEntry>,
+    current_size: u64,
+}
+
+struct DiskInner {
+    dir: PathBuf,
+    max_size: u64,
+    stale_retention: u64,
+    stale_grace: u64,
+    promote_max_size: u64,
+    index: Mutex<DiskIndex>,
+}
+
+// Disk cache tier with its own byte budget and LRU eviction
+#[derive(Clone)]
+pub struct DiskCache {
+    inner: Arc<DiskInner>,
+}
+
+impl DiskCache {
+    // Open the cache directory and rebuild the index from the metadata files.
+    // Leftover temp files and bodies without valid metadata are removed.
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        max_size: u64,
+        time_to_live: u64,
+        stale_retention: u64,
+        stale_grace: u64,
+        promote_max_size: u64,
+    ) -> io::Result<Self> {
+        let dir = dir.into();
+        let tmp = dir.join("tmp");
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+
+        let mut found = Vec::new();
+        for shard in fs::read_dir(&dir)? {
+            let shard = shard?.path();
+            if !shard.is_dir() || shard == tmp {
+                continue;
+            }
+            for file in fs::read_dir(&shard)? {
+                let file = file?.path();
+                if file.extension().is_some_and(|ext| ext == "meta") {
+                    match load_entry(&file, time_to_live) {
+                        Some(entry) => found.push(entry),
+                        None => {
+                            tracing::warn!("Discarding unreadable disk cache entry {}", file.display());
+                            let _ = fs::remove_file(&file);
+                            let _ = fs::remove_file(file.with_extension("body"));
+                        }
+                    }
+                } else if file.extension().is_some_and(|ext| ext == "body") && !file.with_extension("meta").exists() {
+                    let _ = fs::remove_file(&file);
+                }
+            }
+        }
+
+        // Oldest first, so the most recently stored objects end up most recently
+        // used. A key stored twice, left over from a crash, keeps the newer copy.
+        found.sort_by_key(|(_, entry)| entry.timestamp);
+        let mut index = DiskIndex { entries: LruMap::new(), current_size: 0 };
+        let mut replaced = Vec::new();
+        for (key, entry) in found {
+            index.current_size += entry.size;
+            if let Some(old) = index.entries.insert(key, entry) {
+                index.current_size = index.current_size.saturating_sub(old.size);
+                replaced.push(old.path);
+            }
+        }
+
+        let cache = Self {
+            inner: Arc::new(DiskInner {
+                dir,
+                max_size,
+                stale_retention,
+                stale_grace,
+                promote_max_size,
+                index: Mutex::new(index),
+            }),
+        };
+        replaced.extend(cache.evict_to_fit(0));
+        remove_files(replaced);
+
+        let (count, size) = cache.usage();
+        tracing::info!("Disk cache opened at {}: {} entries, {} bytes", cache.inner.dir.display(), count, size);
+        Ok(cache)
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.inner.max_size
+    }
+
+    // Largest disk hit worth copying into the memory tier
+    pub fn promote_max_size(&self) -> u64 {
+        self.inner.promote_max_size
+    }
+
+    pub fn get(&self, key: &str) -> Option<DiskEntry> {
+        let mut index = self.lock();
+        let expired = match index.entries.get_mut(key) {
+            Some(entry) if !super::is_dead(entry.timestamp, entry.ttl, &entry.headers, self.inner.stale_retention, self.inner.stale_grace) => {
+                entry.hits += 1;
+                return Some(entry.clone());
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            if let Some(entry) = index.entries.remove(key) {
+                index.current_size = index.current_size.saturating_sub(entry.size);
+                drop(index);
+                remove_files(vec![entry.path]);
+            }
+        }
+        None
+    }
+
+    // Record a successful revalidation, persisting it in the metadata file
+    pub async fn refresh(&self, key: &str, headers: &HeaderMap, timestamp: u64, ttl: u64) -> io::Result<()> {
+        let entry = {
+            let mut index = self.lock();
+            let Some(entry) = index.entries.get_mut(key) else {
+                return Ok(());
+            };
+            entry.timestamp = timestamp;
+            entry.ttl = ttl;
+            super::merge_headers(&mut entry.headers, headers);
+            entry.clone()
+        };
+
+        let meta_path = entry.path.with_extension("meta");
+        let meta_tmp = self.inner.dir.join("tmp").join(format!("{:016x}.meta", rand::random::<u64>()));
+        write_meta(&meta_tmp, key, &entry).await?;
+        tokio::fs::rename(&meta_tmp, &meta_path).await
+    }
+
+    pub fn remove(&self, key: &str) -> bool {
+        let mut index = self.lock();
+        match index.entries.remove(key) {
+            Some(entry) => {
+                index.current_size = index.current_size.saturating_sub(entry.size);
+                drop(index);
+                remove_files(vec![entry.path]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Remove every entry matching the predicate, returning the removed keys
+    pub fn remove_matching(&self, predicate: impl FnMut(&str, &DiskEntry) -> bool) -> Vec<String> {
+        let mut index = self.lock();
+        let keys = index.entries.keys_matching(predicate);
+        let mut victims = Vec::new();
+        for key in &keys {
+            if let Some(entry) = index.entries.remove(key) {
+                index.current_size = index.current_size.saturating_sub(entry.size);
+                victims.push(entry.path);
+            }
+        }
+        drop(index);
+        remove_files(victims);
+        keys
+    }
+
+    // Snapshot of (key, entry) pairs, most recently used first
+    pub fn entries(&self) -> Vec<(String, DiskEntry)> {
+        let index = self.lock();
+        index.entries.iter().map(|(key, entry)| (key.to_string(), entry.clone())).collect()
+    }
+
+    // (entries, bytes) currently on disk
+    pub fn usage(&self) -> (usize, u64) {
+        let index = self.lock();
+        (index.entries.len(), index.current_size)
+    }
+
+    // Store a complete object
+    pub async fn store(&self, key: String, status: StatusCode, headers: HeaderMap, ttl: u64, data: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer(key, status, headers, ttl, Some(data.len() as u64)).await?;
+        writer.write(data).await?;
+        writer.commit().await
+    }
+
+    // Start writing an object whose body arrives in chunks
+    pub async fn writer(
+        &self,
+        key: String,
+        status: StatusCode,
+        headers: HeaderMap,
+        ttl: u64,
+        expected_size: Option<u64>,
+    ) -> io::Result<DiskWriter> {
+        let tmp_path = self.inner.dir.join("tmp").join(format!("{:016x}", rand::random::<u64>()));
+        let file = tokio::fs::File::create(&tmp_path).await?;
+        Ok(DiskWriter {
+            cache: self.clone(),
+            key,
+            status,
+            headers,
+            ttl,
+            expected_size,
+            tmp_path,
+            file: Some(file),
+            written: 0,
+            digest: None,
+        })
+    }
+
+    // Every write gets a path of its own, so deleting an evicted or replaced
+    // body can never hit a newer one stored under the same key
+    fn body_path(&self, key: &str) -> PathBuf {
+        let name = utils::hex(&Sha256::digest(key.as_bytes()));
+        self.inner.dir.join(&name[..2]).join(format!("{}-{:016x}.body", name, rand::random::<u64>()))
+    }
+
+    // Evict least recently used objects until `incoming` more bytes fit.
+    // Returns the body paths to delete once the lock is released.
+    fn evict_to_fit(&self, incoming: u64) -> Vec<PathBuf> {
+        let mut index = self.lock();
+        let mut victims = Vec::new();
+        while index.current_size + incoming > self.inner.max_size {
+            let Some((key, entry)) = index.entries.pop_lru() else {
+                break;
+            };
+            index.current_size = index.current_size.saturating_sub(entry.size);
+            tracing::debug!("Evicted disk cache entry: {} (size: {}, disk: {}/{})",
+                            key, entry.size, index.current_size, self.inner.max_size);
+            victims.push(entry.path);
+        }
+        victims
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DiskIndex> {
+        match self.inner.index.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+// Writes one object to a temp file and publishes it with a rename, so readers
+// never see a partial body. Dropping an uncommitted writer deletes the temp file.
+pub struct DiskWriter {
+    cache: DiskCache,
+    key: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    ttl: u64,
+    expected_size: Option<u64>,
+    tmp_path: PathBuf,
+    file: Option<tokio::fs::File>,
+    written: u64,
+    // Hash of the body so far and the value it must end up at, for
+    // content-addressed objects
+    digest: Option<(Sha256, String)>,
+}
+
+impl DiskWriter {
+    // Only commit the body if its SHA-256 is `expected` (lowercase hex)
+    pub fn verify_sha256(mut self, expected: String) -> Self {
+        self.digest = Some((Sha256::new(), expected));
+        self
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.written += chunk.len() as u64;
+        if let Some((hasher, _)) = self.digest.as_mut() {
+            hasher.update(chunk);
+        }
+        if self.written > self.cache.max_size() {
+            return Err(io::Error::other("object larger than the disk cache"));
+        }
+        match self.file.as_mut() {
+            Some(file) => file.write_all(chunk).await,
+            None => Err(io::Error::other("disk writer already finished")),
+        }
+    }
+
+    // Whether every byte announced by Content-Length has been written
+    pub fn is_complete(&self) -> bool {
+        self.expected_size == Some(self.written)
+    }
+
+    pub async fn commit(mut self) -> io::Result<()> {
+        if let Some(expected) = self.expected_size.filter(|&expected| expected != self.written) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("expected {} bytes, got {}", expected, self.written),
+            ));
+        }
+        if let Some((hasher, expected)) = self.digest.take() {
+            let actual = utils::hex(&hasher.finalize());
+            if actual != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("body hashes to {}, expected {}", actual, expected),
+                ));
+            }
+        }
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+            file.sync_all().await?;
+        }
+
+        let victims = self.cache.evict_to_fit(self.written);
+        remove_files(victims);
+
+        let body_path = self.cache.body_path(&self.key);
+        if let Some(parent) = body_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let entry = DiskEntry {
+            path: body_path,
+            size: self.written,
+            timestamp: now_secs(),
+            ttl: self.ttl,
+            status: self.status,
+            headers: std::mem::take(&mut self.headers),
+            hits: 0,
+        };
+        let meta_tmp = self.tmp_path.with_extension("meta");
+        write_meta(&meta_tmp, &self.key, &entry).await?;
+
+        // Body first: a meta file is only ever visible next to a complete body
+        tokio::fs::rename(&self.tmp_path, &entry.path).await?;
+        tokio::fs::rename(&meta_tmp, entry.path.with_extension("meta")).await?;
+
+        let mut index = self.cache.lock();
+        index.current_size += entry.size;
+        let replaced = index.entries.insert(self.key.clone(), entry);
+        if let Some(old) = &replaced {
+            index.current_size = index.current_size.saturating_sub(old.size);
+        }
+        tracing::debug!("Stored disk cache entry: {} (size: {}, disk: {}/{})",
+                        self.key, self.written, index.current_size, self.cache.max_size());
+        drop(index);
+        remove_files(replaced.into_iter().map(|old| old.path).collect());
+        Ok(())
+    }
+}
+
+impl Drop for DiskWriter {
+    fn drop(&mut self) {
+        // Still holding the file means commit never finished
+        if self.file.is_some() || self.tmp_path.exists() {
+            remove_files(vec![self.tmp_path.clone()]);
+        }
+    }
+}
+
+async fn write_meta(path: &Path, key: &str, entry: &DiskEntry) -> io::Result<()> {
+    let meta = DiskMeta {
+        key: key.to_string(),
+        size: entry.size,
+        timestamp: entry.timestamp,
+        ttl: Some(entry.ttl),
+        status: entry.status.as_u16(),
+        headers: super::header_pairs(&entry.headers),
+    };
+    let meta_json = serde_json::to_vec(&meta).map_err(io::Error::other)?;
+    tokio::fs::write(path, meta_json).await
+}
+
+fn load_entry(meta_path: &Path, default_ttl: u64) -> Option<(String, DiskEntry)> {
+    let meta: DiskMeta = serde_json::from_slice(&fs::read(meta_path).ok()?).ok()?;
+    let body_path = meta_path.with_extension("body");
+    if fs::metadata(&body_path).ok()?.len() != meta.size {
+        return None;
+    }
+    let headers = super::header_map(meta.headers);
+    let entry = DiskEntry {
+        path: body_path,
+        size: meta.size,
+        timestamp: meta.timestamp,
+        ttl: meta.ttl.unwrap_or(default_ttl),
+        status: StatusCode::from_u16(meta.status).ok()?,
+        headers,
+        hits: 0,
+    };
+    Some((meta.key, entry))
+}
+
+// Delete bodies and their metadata. Unlinking a multi-gigabyte file can take
+// a while, so on the runtime it happens on a blocking thread.
+fn remove_files(bodies: Vec<PathBuf>) {
+    if bodies.is_empty() {
+        return;
+    }
+    let remove = move || {
+        for body in bodies {
+            let _ = fs::remove_file(body.with_extension("meta"));
+            let _ = fs::remove_file(&body);
+        }
+    };
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => drop(runtime.spawn_blocking(remove)),
+        Err(_) => remove(),
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn open(dir: &Path, max_size: u64) -> DiskCache {
+        DiskCache::open(dir, max_size, 3600, 0, 0, 0).unwrap()
+    }
+
+    async fn store(cache: &DiskCache, key: &str, body: &[u8]) {
+        cache.store(key.to_string(), StatusCode::OK, HeaderMap::new(), 3600, body).await.unwrap();
+    }
+
+    // Files under the cache directory with the given extension
+    fn files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+        let mut found = Vec::new();
+        for shard in fs::read_dir(dir).unwrap() {
+            let shard = shard.unwrap().path();
+            if shard.is_dir() && shard.file_name().is_some_and(|name| name != "tmp") {
+                for file in fs::read_dir(&shard).unwrap() {
+                    let file = file.unwrap().path();
+                    if file.extension().is_some_and(|ext| ext == extension) {
+                        found.push(file);
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    fn temp_files(dir: &Path) -> usize {
+        fs::read_dir(dir.join("tmp")).unwrap().count()
+    }
+
+    // Deletions run on a blocking thread
+    async fn settle<T: PartialEq + std::fmt::Debug>(expected: T, mut actual: impl FnMut() -> T) {
+        for _ in 0..200 {
+            if actual() == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(actual(), expected);
+    }
+
+    #[tokio::test]
+    async fn commit_publishes_whole_bodies_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(dir.path(), 1 << 20);
+
+        store(&cache, "complete", b"hello").await;
+        let entry = cache.get("complete").unwrap();
+        assert_eq!(fs::read(&entry.path).unwrap(), b"hello");
+        assert!(entry.path.with_extension("meta").exists());
+
+        // Dropped mid-body
+        let mut writer = cache.writer("dropped".to_string(), StatusCode::OK, HeaderMap::new(), 3600, Some(10)).await.unwrap();
+        writer.write(b"hel").await.unwrap();
+        drop(writer);
+
+        // Shorter than announced
+        let mut writer = cache.writer("short".to_string(), StatusCode::OK, HeaderMap::new(), 3600, Some(10)).await.unwrap();
+        writer.write(b"hel").await.unwrap();
+        assert_eq!(writer.commit().await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // Not the content its key promises
+        let mut writer = cache.writer("hashed".to_string(), StatusCode::OK, HeaderMap::new(), 3600, None).await.unwrap();
+        writer = writer.verify_sha256(utils::hex(&Sha256::digest(b"expected")));
+        writer.write(b"tampered").await.unwrap();
+        assert_eq!(writer.commit().await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        assert!(cache.get("dropped").is_none());
+        assert!(cache.get("short").is_none());
+        assert!(cache.get("hashed").is_none());
+        assert_eq!(cache.usage(), (1, 5));
+        assert_eq!(files(dir.path(), "body").len(), 1);
+        settle(0, || temp_files(dir.path())).await;
+    }
+
+    #[tokio::test]
+    async fn index_is_rebuilt_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let cache = open(dir.path(), 1 << 20);
+            store(&cache, "first", b"one").await;
+            store(&cache, "second", b"two!").await;
+            store(&cache, "broken", b"three").await;
+        }
+        // Damage left behind by a crash: a torn metadata file, an orphaned
+        // body and an unfinished temp file
+        let broken = open(dir.path(), 1 << 20).get("broken").unwrap().path;
+        fs::write(broken.with_extension("meta"), b"{").unwrap();
+        let orphan = broken.with_file_name("orphan.body");
+        fs::write(&orphan, b"orphan").unwrap();
+        fs::write(dir.path().join("tmp").join("leftover"), b"partial").unwrap();
+
+        let cache = open(dir.path(), 1 << 20);
+        assert_eq!(cache.usage(), (2, 7));
+        assert_eq!(fs::read(cache.get("first").unwrap().path).unwrap(), b"one");
+        assert_eq!(fs::read(cache.get("second").unwrap().path).unwrap(), b"two!");
+        assert!(cache.get("broken").is_none());
+        assert!(!broken.exists());
+        assert!(!orphan.exists());
+        assert_eq!(temp_files(dir.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn eviction_keeps_size_within_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(dir.path(), 100);
+
+        store(&cache, "a", &[b'a'; 40]).await;
+        store(&cache, "b", &[b'b'; 40]).await;
+        // "a" becomes the most recently used
+        assert!(cache.get("a").is_some());
+        store(&cache, "c", &[b'c'; 40]).await;
+
+        assert_eq!(cache.usage(), (2, 80));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        settle(2, || files(dir.path(), "body").len()).await;
+        settle(2, || files(dir.path(), "meta").len()).await;
+
+        // Reopening with a smaller budget evicts down to it
+        drop(cache);
+        let cache = open(dir.path(), 50);
+        assert_eq!(cache.usage(), (1, 40));
+        settle(1, || files(dir.path(), "body").len()).await;
+    }
+
+    #[tokio::test]
+    async fn replaced_bodies_are_removed_without_touching_the_new_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(dir.path(), 1 << 20);
+
+        store(&cache, "key", b"old").await;
+        let old = cache.get("key").unwrap().path;
+        store(&cache, "key", b"newer").await;
+        let new = cache.get("key").unwrap().path;
+
+        assert_ne!(old, new);
+        assert_eq!(cache.usage(), (1, 5));
+        settle(false, || old.exists()).await;
+        assert_eq!(fs::read(&new).unwrap(), b"newer");
+
+        // Two copies of a key found on open: the newer one wins
+        let stale = new.with_file_name("stale.body");
+        fs::write(&stale, b"ancient").unwrap();
+        let meta = DiskMeta {
+            key: "key".to_string(),
+            size: 7,
+            timestamp: 1,
+            ttl: Some(u64::MAX / 4),
+            status: 200,
+            headers: Vec::new(),
+        };
+        fs::write(stale.with_extension("meta"), serde_json::to_vec(&meta).unwrap()).unwrap();
+        drop(cache);
+        let cache = open(dir.path(), 1 << 20);
+        assert_eq!(fs::read(cache.get("key").unwrap().path).unwrap(), b"newer");
+        assert_eq!(cache.usage(), (1, 5));
+        settle(false, || stale.exists()).await;
+    }
+}
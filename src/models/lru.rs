@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+const NIL: usize = usize::MAX;
+
+struct Node<V> {
+    key: String,
+    value: V,
+    prev: usize,
+    next: usize,
+}
+
+// Hash map with a recency list threaded through a slab of nodes, so lookups,
+// promotions, inserts and evictions are all O(1). `head` is the most recently
+// used entry, `tail` the least recently used one.
+pub struct LruMap<V> {
+    index: HashMap<String, usize>,
+    nodes: Vec<Option<Node<V>>>,
+    free: Vec<usize>,
+    head: usize,
+    tail: usize,
+}
+
+impl<V> Default for LruMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> LruMap<V> {
+    pub fn new() -> Self {
+        Self {
+            index: HashMap::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    // Mutable lookup, also marking the entry most recently used
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        let idx = *self.index.get(key)?;
+        self.detach(idx);
+        self.push_front(idx);
+        self.nodes[idx].as_mut().map(|node| &mut node.value)
+    }
+
+    // Insert or replace an entry as the most recently used; returns the old value
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        if let Some(&idx) = self.index.get(&key) {
+            self.detach(idx);
+            self.push_front(idx);
+            let node = self.nodes[idx].as_mut()?;
+            return Some(std::mem::replace(&mut node.value, value));
+        }
+
+        let node = Node { key: key.clone(), value, prev: NIL, next: NIL };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = Some(node);
+                idx
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        self.index.insert(key, idx);
+        self.push_front(idx);
+        None
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let idx = self.index.remove(key)?;
+        self.detach(idx);
+        self.free.push(idx);
+        self.nodes[idx].take().map(|node| node.value)
+    }
+
+    // Entries from most to least recently used
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        let mut idx = self.head;
+        std::iter::from_fn(move || {
+            let node = self.nodes.get(idx)?.as_ref()?;
+            idx = node.next;
+            Some((node.key.as_str(), &node.value))
+        })
+    }
+
+    // Keys of entries matching a predicate, for bulk removal
+    pub fn keys_matching(&self, mut predicate: impl FnMut(&str, &V) -> bool) -> Vec<String> {
+        self.iter()
+            .filter(|(key, value)| predicate(key, value))
+            .map(|(key, _)| key.to_string())
+            .collect()
+    }
+
+    // The entry that would be evicted next
+    pub fn peek_lru(&self) -> Option<(&str, &V)> {
+        self.nodes
+            .get(self.tail)?
+            .as_ref()
+            .map(|node| (node.key.as_str(), &node.value))
+    }
+
+    pub fn pop_lru(&mut self) -> Option<(String, V)> {
+        let idx = self.tail;
+        let key = self.nodes.get(idx)?.as_ref()?.key.clone();
+        self.remove(&key).map(|value| (key, value))
+    }
+
+    fn detach(&mut self, idx: usize) {
+        let (prev, next) = match self.nodes[idx].as_ref() {
+            Some(node) => (node.prev, node.next),
+            None => return,
+        };
+        match prev {
+            NIL => self.head = next,
+            prev => {
+                if let Some(node) = self.nodes[prev].as_mut() {
+                    node.next = next;
+                }
+            }
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => {
+                if let Some(node) = self.nodes[next].as_mut() {
+                    node.prev = prev;
+                }
+            }
+        }
+    }
+
+    fn push_front(&mut self, idx: usize) {
+        let old_head = self.head;
+        if let Some(node) = self.nodes[idx].as_mut() {
+            node.prev = NIL;
+            node.next = old_head;
+        }
+        if let Some(node) = self.nodes.get_mut(old_head).and_then(Option::as_mut) {
+            node.prev = idx;
+        }
+        self.head = idx;
+        if self.tail == NIL {
+            self.tail = idx;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys<V>(map: &LruMap<V>) -> Vec<&str> {
+        map.iter().map(|(key, _)| key).collect()
+    }
+
+    fn filled(keys: &[&str]) -> LruMap<usize> {
+        let mut map = LruMap::new();
+        for (value, key) in keys.iter().enumerate() {
+            map.insert(key.to_string(), value);
+        }
+        map
+    }
+
+    #[test]
+    fn pop_evicts_least_recently_inserted() {
+        let mut map = filled(&["a", "b", "c"]);
+        assert_eq!(keys(&map), ["c", "b", "a"]);
+        assert_eq!(map.peek_lru(), Some(("a", &0)));
+        assert_eq!(map.pop_lru(), Some(("a".to_string(), 0)));
+        assert_eq!(map.pop_lru(), Some(("b".to_string(), 1)));
+        assert_eq!(map.pop_lru(), Some(("c".to_string(), 2)));
+        assert_eq!(map.pop_lru(), None);
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn get_mut_promotes() {
+        let mut map = filled(&["a", "b", "c"]);
+        *map.get_mut("a").unwrap() += 10;
+        assert_eq!(keys(&map), ["a", "c", "b"]);
+        assert_eq!(map.pop_lru(), Some(("b".to_string(), 1)));
+
+        // The tail itself moving to the front leaves the next one as victim
+        map.get_mut("c");
+        assert_eq!(map.peek_lru(), Some(("a", &10)));
+        assert!(map.get_mut("missing").is_none());
+        assert_eq!(keys(&map), ["c", "a"]);
+    }
+
+    #[test]
+    fn insert_replaces_and_promotes() {
+        let mut map = filled(&["a", "b", "c"]);
+        assert_eq!(map.insert("a".to_string(), 7), Some(0));
+        assert_eq!(map.len(), 3);
+        assert_eq!(keys(&map), ["a", "c", "b"]);
+        assert_eq!(map.pop_lru(), Some(("b".to_string(), 1)));
+    }
+
+    #[test]
+    fn removed_slots_are_reused() {
+        let mut map = filled(&["a", "b", "c"]);
+        assert_eq!(map.remove("b"), Some(1));
+        assert_eq!(map.remove("b"), None);
+        assert_eq!(keys(&map), ["c", "a"]);
+
+        map.insert("d".to_string(), 3);
+        assert_eq!(map.nodes.len(), 3);
+        assert_eq!(keys(&map), ["d", "c", "a"]);
+        assert_eq!(map.keys_matching(|_, value| *value != 2), ["d", "a"]);
+
+        map.remove("a");
+        map.remove("d");
+        map.remove("c");
+        assert_eq!(map.peek_lru(), None);
+        map.insert("e".to_string(), 4);
+        assert_eq!(keys(&map), ["e"]);
+        assert_eq!(map.peek_lru(), Some(("e", &4)));
+    }
+}
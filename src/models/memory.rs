@@ -0,0 +1,413 @@
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{merge_headers, BackendUsage, CacheBackend, CacheEntry, CacheStats, EntrySummary, FrequencySketch, LruMap};
+use crate::config::AdmissionPolicy;
+use crate::error::Result;
+
+// Entries of one shard in recency order plus the access history used for admission
+struct CacheStore {
+    entries: LruMap<CacheEntry>,
+    sketch: Option<FrequencySketch>,
+}
+
+// In-process cache with a memory limit. Keys are spread over independently
+// locked shards so requests for different objects don't queue behind one lock;
+// memory use and entry count are global atomics. Recency is tracked per shard,
+// so eviction order is only approximately LRU across the whole cache.
+pub struct MemoryBackend {
+    shards: Box<[Mutex<CacheStore>]>,
+    max_capacity: usize,        // Maximum number of entries
+    max_memory: usize,          // Maximum memory in bytes
+    current_memory: AtomicUsize,  // Current memory usage
+    entry_count: AtomicUsize,
+    stats: Arc<CacheStats>,
+}
+
+impl MemoryBackend {
+    pub fn new(max_capacity: usize, max_memory: usize, admission: AdmissionPolicy, stats: Arc<CacheStats>) -> Self {
+        Self::with_shards(shard_count(), max_capacity, max_memory, admission, stats)
+    }
+
+    // `shard_count` must be a power of two
+    fn with_shards(
+        shard_count: usize,
+        max_capacity: usize,
+        max_memory: usize,
+        admission: AdmissionPolicy,
+        stats: Arc<CacheStats>,
+    ) -> Self {
+        let per_shard = max_capacity.div_ceil(shard_count);
+        let shards = (0..shard_count)
+            .map(|_| {
+                Mutex::new(CacheStore {
+                    entries: LruMap::new(),
+                    // TinyLFU keeps a frequency sketch so new entries compete with the eviction victim
+                    sketch: (admission == AdmissionPolicy::TinyLfu).then(|| FrequencySketch::new(per_shard)),
+                })
+            })
+            .collect();
+        Self {
+            shards,
+            max_capacity,
+            max_memory,
+            current_memory: AtomicUsize::new(0),
+            entry_count: AtomicUsize::new(0),
+            stats,
+        }
+    }
+
+    // Whether adding `charge` more bytes (and one more entry, if non-zero) would exceed a limit
+    fn over_budget(&self, charge: usize) -> bool {
+        let entries = self.entry_count.load(Ordering::Relaxed) + usize::from(charge > 0);
+        self.current_memory.load(Ordering::Relaxed) + charge > self.max_memory
+            || entries > self.max_capacity
+    }
+
+    // Pop least recently used entries from one shard while over budget
+    fn evict(&self, store: &mut CacheStore, charge: usize) {
+        while self.over_budget(charge) {
+            let Some((key_to_remove, removed_entry)) = store.entries.pop_lru() else { break };
+            self.release(removed_entry.charge);
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!("Evicted least recently used cache entry: {} (size: {}, memory: {}/{}, entries: {}/{})",
+                            key_to_remove, removed_entry.charge, self.current_memory.load(Ordering::Relaxed),
+                            self.max_memory, self.entry_count.load(Ordering::Relaxed), self.max_capacity);
+        }
+    }
+
+    // Account for an entry that left the cache
+    fn release(&self, size: usize) {
+        self.current_memory.fetch_sub(size, Ordering::Relaxed);
+        self.entry_count.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn shard_of(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        // The shard count is a power of two
+        hasher.finish() as usize & (self.shards.len() - 1)
+    }
+
+    fn lock_shard(&self, index: usize) -> MutexGuard<'_, CacheStore> {
+        match self.shards[index].lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+#[async_trait]
+impl CacheBackend for MemoryBackend {
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>> {
+        // Lookups reorder the recency list, so even reads lock the shard exclusively
+        let mut store = self.lock_shard(self.shard_of(key));
+        if let Some(sketch) = store.sketch.as_mut() {
+            sketch.increment(key);
+        }
+        Ok(store.entries.get_mut(key).map(|entry| {
+            entry.hits += 1;
+            // Cloning shares the body instead of copying it
+            entry.clone()
+        }))
+    }
+
+    async fn set(&self, key: String, mut entry: CacheEntry, _retain: u64) -> Result<bool> {
+        let charge = entry_charge(&key, &entry.headers, entry.size);
+
+        // Check if data is too large
+        if charge > self.max_memory {
+            tracing::warn!("Data size {} exceeds maximum memory limit {}, not caching", charge, self.max_memory);
+            return Ok(false);
+        }
+        entry.charge = charge;
+
+        let shard = self.shard_of(&key);
+        {
+            let mut store = self.lock_shard(shard);
+
+            // A replaced entry frees its own space first
+            let replacing = match store.entries.remove(&key) {
+                Some(old_entry) => {
+                    self.release(old_entry.charge);
+                    true
+                }
+                None => false,
+            };
+
+            // TinyLFU: only displace the LRU victim if the newcomer has been asked
+            // for more often recently. Refreshing an entry already held is always allowed.
+            if self.over_budget(charge) && !replacing {
+                if let (Some(sketch), Some((victim_key, _))) = (store.sketch.as_ref(), store.entries.peek_lru()) {
+                    let candidate = sketch.frequency(&key);
+                    let victim = sketch.frequency(victim_key);
+                    if candidate <= victim {
+                        self.stats.rejections.fetch_add(1, Ordering::Relaxed);
+                        tracing::debug!("Admission rejected for {} (frequency {} <= victim {} frequency {})",
+                                        key, candidate, victim_key, victim);
+                        return Ok(false);
+                    }
+                }
+            }
+
+            // Evict least recently used entries of this shard until the new one
+            // fits. Other shards may hold older entries; LRU is per shard.
+            self.evict(&mut store, charge);
+
+            self.current_memory.fetch_add(charge, Ordering::Relaxed);
+            self.entry_count.fetch_add(1, Ordering::Relaxed);
+            store.entries.insert(key, entry);
+        }
+
+        // This shard ran dry before the budget was met: take the rest from the
+        // others, one lock at a time so two inserts can never deadlock
+        for offset in 1..self.shards.len() {
+            if !self.over_budget(0) {
+                break;
+            }
+            let mut store = self.lock_shard((shard + offset) % self.shards.len());
+            self.evict(&mut store, 0);
+        }
+        Ok(true)
+    }
+
+    async fn refresh(&self, key: &str, headers: &HeaderMap, timestamp: u64, ttl: u64, _retain: u64) -> Result<()> {
+        if let Some(entry) = self.lock_shard(self.shard_of(key)).entries.get_mut(key) {
+            entry.timestamp = timestamp;
+            entry.ttl = ttl;
+            merge_headers(&mut entry.headers, headers);
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool> {
+        let removed = self.lock_shard(self.shard_of(key)).entries.remove(key);
+        if let Some(removed_entry) = &removed {
+            self.release(removed_entry.charge);
+        }
+        Ok(removed.is_some())
+    }
+
+    async fn remove_matching(&self, predicate: &(dyn for<'k> Fn(&'k str) -> bool + Sync)) -> Result<Vec<String>> {
+        let mut removed = Vec::new();
+        for index in 0..self.shards.len() {
+            let mut store = self.lock_shard(index);
+            for key in store.entries.keys_matching(|key, _| predicate(key)) {
+                if let Some(removed_entry) = store.entries.remove(&key) {
+                    self.release(removed_entry.charge);
+                    removed.push(key);
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn sweep(&self, is_dead: &(dyn for<'e> Fn(&'e CacheEntry) -> bool + Sync)) -> Result<usize> {
+        let mut removed = 0;
+        for index in 0..self.shards.len() {
+            let mut store = self.lock_shard(index);
+            for key in store.entries.keys_matching(|_, entry| is_dead(entry)) {
+                if let Some(removed_entry) = store.entries.remove(&key) {
+                    self.release(removed_entry.charge);
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    // Each shard most recently used first
+    async fn entries(&self) -> Result<Vec<EntrySummary>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut summaries = Vec::new();
+        for index in 0..self.shards.len() {
+            let store = self.lock_shard(index);
+            summaries.extend(store.entries.iter().map(|(key, entry)| EntrySummary {
+                key: key.to_string(),
+                tier: "memory",
+                size: entry.size as u64,
+                age: now.saturating_sub(entry.timestamp),
+                ttl: entry.ttl,
+                hits: entry.hits,
+            }));
+        }
+        Ok(summaries)
+    }
+
+    fn max_object_size(&self) -> usize {
+        self.max_memory
+    }
+
+    fn usage(&self) -> BackendUsage {
+        BackendUsage {
+            kind: "memory",
+            entries: Some(self.entry_count.load(Ordering::Relaxed)),
+            max_entries: Some(self.max_capacity),
+            bytes: Some(self.current_memory.load(Ordering::Relaxed)),
+            max_bytes: Some(self.max_memory),
+        }
+    }
+}
+
+// A few shards per core keeps lock contention low without spreading small
+// caches too thin
+fn shard_count() -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    (cores * 4).next_power_of_two().min(64)
+}
+
+// Approximate heap and bookkeeping cost of an in-memory entry. The key is held
+// twice (recency list and index) and every header carries its own allocation.
+fn entry_charge(key: &str, headers: &HeaderMap, data_size: usize) -> usize {
+    const ENTRY_OVERHEAD: usize = std::mem::size_of::<CacheEntry>()
+        + 2 * std::mem::size_of::<String>()
+        + 4 * std::mem::size_of::<usize>();
+    const HEADER_OVERHEAD: usize = 64;
+    let header_bytes: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + HEADER_OVERHEAD)
+        .sum();
+    data_size + 2 * key.len() + header_bytes + ENTRY_OVERHEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use bytes::Bytes;
+    use std::time::Instant;
+
+    const THREADS: usize = 8;
+    const OPERATIONS: usize = 200_000;
+    const KEYS: u64 = 20_000;
+
+    fn entry(data: &Bytes) -> CacheEntry {
+        CacheEntry {
+            data: data.clone(),
+            timestamp: 0,
+            ttl: 3600,
+            size: data.len(),
+            charge: 0,
+            hits: 0,
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    fn two_entry_backend(admission: AdmissionPolicy) -> (MemoryBackend, Arc<CacheStats>) {
+        let stats = Arc::new(CacheStats::default());
+        (MemoryBackend::with_shards(1, 2, usize::MAX / 2, admission, stats.clone()), stats)
+    }
+
+    async fn request(backend: &MemoryBackend, key: &str, times: usize) {
+        for _ in 0..times {
+            backend.get(key).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn tiny_lfu_admits_only_newcomers_hotter_than_the_victim() {
+        let (backend, stats) = two_entry_backend(AdmissionPolicy::TinyLfu);
+        let body = Bytes::from_static(b"body");
+        backend.set("a".to_string(), entry(&body), 0).await.unwrap();
+        backend.set("b".to_string(), entry(&body), 0).await.unwrap();
+        request(&backend, "a", 3).await;
+        request(&backend, "b", 3).await;
+
+        // Never asked for before: loses to "a", the least recently used
+        assert!(!backend.set("c".to_string(), entry(&body), 0).await.unwrap());
+        assert_eq!(stats.rejections.load(Ordering::Relaxed), 1);
+        assert!(backend.get("c").await.unwrap().is_none());
+
+        // Replacing an entry already held is not an admission
+        assert!(backend.set("b".to_string(), entry(&body), 0).await.unwrap());
+
+        // Asked for more often than "a" now
+        request(&backend, "c", 3).await;
+        assert!(backend.set("c".to_string(), entry(&body), 0).await.unwrap());
+        assert_eq!(stats.rejections.load(Ordering::Relaxed), 1);
+        assert!(backend.get("a").await.unwrap().is_none());
+        assert!(backend.get("b").await.unwrap().is_some());
+        assert!(backend.get("c").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn always_admits_and_evicts_the_least_recently_used() {
+        let (backend, stats) = two_entry_backend(AdmissionPolicy::Always);
+        let body = Bytes::from_static(b"body");
+        backend.set("a".to_string(), entry(&body), 0).await.unwrap();
+        backend.set("b".to_string(), entry(&body), 0).await.unwrap();
+        request(&backend, "b", 3).await;
+        request(&backend, "a", 3).await;
+
+        assert!(backend.set("c".to_string(), entry(&body), 0).await.unwrap());
+        assert_eq!(stats.rejections.load(Ordering::Relaxed), 0);
+        assert!(backend.get("b").await.unwrap().is_none());
+        assert!(backend.get("a").await.unwrap().is_some());
+        assert!(backend.get("c").await.unwrap().is_some());
+    }
+
+    // Operations per second of THREADS threads each running OPERATIONS gets,
+    // with a set after every miss, over a skewed key distribution. Each thread
+    // draws its keys from a fixed-seed generator, so runs are repeatable.
+    fn throughput(backend: &Arc<MemoryBackend>) -> (f64, f64) {
+        let data = Bytes::from(vec![0u8; 1024]);
+        let started = Instant::now();
+        let threads: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let (backend, data) = (backend.clone(), data.clone());
+                std::thread::spawn(move || {
+                    let mut state = 0x9e37_79b9_7f4a_7c15u64 ^ thread as u64;
+                    let mut hits = 0usize;
+                    for _ in 0..OPERATIONS {
+                        // xorshift64; squaring the draw favours low keys
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        let draw = (state >> 11) as f64 / (1u64 << 53) as f64;
+                        let key = format!("https://github.com/o/r/raw/main/{}", (draw * draw * KEYS as f64) as u64);
+                        let found = futures::executor::block_on(backend.get(&key)).unwrap();
+                        match found {
+                            Some(_) => hits += 1,
+                            None => {
+                                futures::executor::block_on(backend.set(key, entry(&data), 0)).unwrap();
+                            }
+                        }
+                    }
+                    hits
+                })
+            })
+            .collect();
+        let hits: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        let total = (THREADS * OPERATIONS) as f64;
+        (total / started.elapsed().as_secs_f64(), hits as f64 / total)
+    }
+
+    // Single lock versus the sharded default, both holding a quarter of the
+    // keys. Run with:
+    //   cargo test --release memory_backend_throughput -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn memory_backend_throughput() {
+        let capacity = (KEYS / 4) as usize;
+        for shards in [1, shard_count()] {
+            for admission in [AdmissionPolicy::Always, AdmissionPolicy::TinyLfu] {
+                let stats = Arc::new(CacheStats::default());
+                let backend = Arc::new(MemoryBackend::with_shards(shards, capacity, usize::MAX / 2, admission, stats));
+                let (rate, hit_ratio) = throughput(&backend);
+                println!(
+                    "{:>2} shards, {:?}: {:>10.0} ops/s, hit ratio {:.3}",
+                    shards, admission, rate, hit_ratio
+                );
+            }
+        }
+    }
+}
@@ -1,163 +1,407 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use serde::Serialize;
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::error::Result;
+
+mod backend;
+mod disk;
+mod lru;
+mod memory;
+mod policy;
+mod redis;
+mod sketch;
+pub use backend::{BackendUsage, CacheBackend};
+pub use disk::*;
+pub use memory::MemoryBackend;
+pub use self::redis::RedisBackend;
+pub use lru::LruMap;
+pub use policy::{allows_authorized_storage, allows_stale, is_private, CachePolicy};
+pub use sketch::FrequencySketch;
+
 // Cache entry structure
 #[derive(Clone)]
 pub struct CacheEntry {
-    pub data: Vec<u8>,
+    pub data: Bytes,
     pub timestamp: u64,
+    // Seconds the entry stays fresh, from the cache policy
+    pub ttl: u64,
     pub size: usize,
+    // Bytes charged against max_memory: the body plus key and metadata overhead
+    pub charge: usize,
+    pub hits: u64,
+    // Upstream status and the response headers worth replaying on a hit
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+}
+
+// One cache entry as reported by the admin API
+#[derive(Serialize)]
+pub struct EntrySummary {
+    pub key: String,
+    pub tier: &'static str,
+    pub size: u64,
+    pub age: u64,
+    pub ttl: u64,
+    pub hits: u64,
+}
+
+// Where the body of a cache hit lives
+#[derive(Clone)]
+pub enum CachedBody {
+    Memory(Bytes),
+    // Streamed from the file rather than read into memory
+    Disk(PathBuf),
+}
+
+// A cache hit from either tier
+#[derive(Clone)]
+pub struct CachedObject {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub timestamp: u64,
+    pub ttl: u64,
+    pub size: u64,
+    pub body: CachedBody,
+    // Past its TTL; revalidated before it is served unless stale serving allows otherwise
+    pub stale: bool,
 }
 
-// Cache structure with memory limit
+impl CachedObject {
+    // Seconds since the object expired
+    pub fn staleness(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        now.saturating_sub(self.timestamp.saturating_add(self.ttl))
+    }
+}
+
+// Cache counters
+#[derive(Default)]
+pub struct CacheStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub evictions: AtomicU64,
+    pub rejections: AtomicU64,
+    pub revalidations: AtomicU64,
+    pub expirations: AtomicU64,
+    // Expired entries served in place of a refresh or an upstream error
+    pub stale: AtomicU64,
+}
+
+// The response cache: a backend holding the entries plus an optional local
+// disk tier behind it, and the policy deciding what goes in and for how long
 #[derive(Clone)]
 pub struct AppCache {
-    pub cache: Arc<RwLock<HashMap<String, CacheEntry>>>,
+    backend: Arc<dyn CacheBackend>,
     pub enabled: bool,
-    pub max_capacity: usize,        // Maximum number of entries
-    pub max_memory: usize,          // Maximum memory in bytes
-    pub time_to_live: u64,
-    pub current_memory: Arc<RwLock<usize>>,  // Current memory usage
+    pub stale_retention: u64,       // How long expired entries are kept for revalidation
+    pub stale_while_revalidate: u64, // How long past expiry an entry is served while refreshed
+    pub stale_if_error: u64,        // How long past expiry an entry is served when upstream fails
+    pub stats: Arc<CacheStats>,
+    pub disk: Option<DiskCache>,
+    pub policy: Arc<CachePolicy>,
 }
 
 impl AppCache {
-    pub fn new(enabled: bool, max_capacity: usize, time_to_live: u64) -> Self {
+    // `stats` is shared with the backend, which counts its own evictions and rejections
+    pub fn new(enabled: bool, backend: Arc<dyn CacheBackend>, stats: Arc<CacheStats>, time_to_live: u64) -> Self {
         Self {
-            cache: Arc::new(RwLock::new(HashMap::new())),
+            backend,
             enabled,
-            max_capacity,
-            max_memory: 100 * 1024 * 1024, // 100MB default limit
-            time_to_live,
-            current_memory: Arc::new(RwLock::new(0)),
+            stale_retention: 0,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+            stats,
+            disk: None,
+            policy: Arc::new(CachePolicy::default_ttl(time_to_live)),
         }
     }
 
-    pub fn with_memory_limit(enabled: bool, max_capacity: usize, max_memory: usize, time_to_live: u64) -> Self {
-        Self {
-            cache: Arc::new(RwLock::new(HashMap::new())),
-            enabled,
-            max_capacity,
-            max_memory,
-            time_to_live,
-            current_memory: Arc::new(RwLock::new(0)),
-        }
+    // Keep expired entries that carry validators this long past their TTL
+    pub fn with_stale_retention(mut self, stale_retention: u64) -> Self {
+        self.stale_retention = stale_retention;
+        self
+    }
+
+    // Allow serving expired entries in place of a blocking refresh or an upstream error
+    pub fn with_stale_serving(mut self, stale_while_revalidate: u64, stale_if_error: u64) -> Self {
+        self.stale_while_revalidate = stale_while_revalidate;
+        self.stale_if_error = stale_if_error;
+        self
+    }
+
+    // How long past expiry any entry is kept, validators or not
+    pub fn stale_grace(&self) -> u64 {
+        self.stale_while_revalidate.max(self.stale_if_error)
     }
 
-    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+    // Replace the global TTL with per-URL rules
+    pub fn with_policy(mut self, policy: CachePolicy) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    // Put a disk tier behind the backend
+    pub fn with_disk(mut self, disk: DiskCache) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    // Look in the backend first, then on disk. Expired objects that carry a validator
+    // are still returned, marked stale, so they can be revalidated upstream.
+    pub async fn lookup(&self, key: &str) -> Option<CachedObject> {
         if !self.enabled {
             return None;
         }
 
-        let cache = self.cache.read().ok()?;
-        if let Some(entry) = cache.get(key) {
-            let current_time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .ok()?
-                .as_secs();
-            
-            if current_time - entry.timestamp < self.time_to_live {
-                Some(entry.data.clone())
-            } else {
-                // Entry expired
-                drop(cache);
-                self.remove(key);
-                None
+        let object = match self.get_entry(key).await {
+            Some(entry) => Some(CachedObject {
+                status: entry.status,
+                stale: is_expired(entry.timestamp, entry.ttl),
+                headers: entry.headers,
+                timestamp: entry.timestamp,
+                ttl: entry.ttl,
+                size: entry.size as u64,
+                body: CachedBody::Memory(entry.data),
+            }),
+            None => self.disk.as_ref().and_then(|disk| disk.get(key)).map(|entry| CachedObject {
+                status: entry.status,
+                stale: is_expired(entry.timestamp, entry.ttl),
+                headers: entry.headers,
+                timestamp: entry.timestamp,
+                ttl: entry.ttl,
+                size: entry.size,
+                body: CachedBody::Disk(entry.path),
+            }),
+        };
+
+        match &object {
+            Some(object) if !object.stale => self.stats.hits.fetch_add(1, Ordering::Relaxed),
+            _ => self.stats.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        object
+    }
+
+    // Mark an object as fresh again after upstream answered 304 Not Modified,
+    // taking over any updated headers. Returns the new timestamp.
+    pub async fn refresh(&self, key: &str, headers: &HeaderMap, ttl: u64) -> u64 {
+        let timestamp = now_secs();
+        self.stats.revalidations.fetch_add(1, Ordering::Relaxed);
+
+        // Only entries with a validator get revalidated
+        let retain = ttl.saturating_add(self.stale_retention.max(self.stale_grace()));
+        if let Err(e) = self.backend.refresh(key, headers, timestamp, ttl, retain).await {
+            tracing::warn!("Failed to refresh cache entry {}: {}", key, e);
+        }
+
+        if let Some(disk) = self.disk.as_ref() {
+            if let Err(e) = disk.refresh(key, headers, timestamp, ttl).await {
+                tracing::warn!("Failed to refresh disk cache entry {}: {}", key, e);
             }
-        } else {
-            None
         }
+        timestamp
+    }
+
+    async fn get_entry(&self, key: &str) -> Option<CacheEntry> {
+        let entry = match self.backend.get(key).await {
+            Ok(entry) => entry?,
+            Err(e) => {
+                tracing::warn!("Cache lookup for {} failed: {}", key, e);
+                return None;
+            }
+        };
+        if !is_dead(entry.timestamp, entry.ttl, &entry.headers, self.stale_retention, self.stale_grace()) {
+            return Some(entry);
+        }
+
+        // Entry expired
+        if let Ok(true) = self.backend.remove(key).await {
+            self.stats.expirations.fetch_add(1, Ordering::Relaxed);
+        }
+        None
     }
 
-    pub fn set(&self, key: String, data: Vec<u8>) -> bool {
+    // Drop entries that can no longer be served or revalidated from both tiers.
+    // Returns the number of entries removed.
+    pub async fn sweep(&self) -> usize {
+        let (retention, grace) = (self.stale_retention, self.stale_grace());
+        let mut removed = match self
+            .backend
+            .sweep(&|entry| is_dead(entry.timestamp, entry.ttl, &entry.headers, retention, grace))
+            .await
+        {
+            Ok(removed) => removed,
+            Err(e) => {
+                tracing::warn!("Failed to sweep cache: {}", e);
+                0
+            }
+        };
+        if let Some(disk) = self.disk.as_ref() {
+            removed += disk
+                .remove_matching(|_, entry| is_dead(entry.timestamp, entry.ttl, &entry.headers, retention, grace))
+                .len();
+        }
+        self.stats.expirations.fetch_add(removed as u64, Ordering::Relaxed);
+        removed
+    }
+
+    // Run `sweep` every `interval` for as long as the process lives
+    pub fn spawn_sweeper(&self, interval: std::time::Duration) {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let removed = cache.sweep().await;
+                if removed > 0 {
+                    tracing::info!("Swept {} expired cache entries", removed);
+                }
+            }
+        });
+    }
+
+    pub async fn set(&self, key: String, status: StatusCode, headers: HeaderMap, ttl: u64, data: Bytes) -> bool {
+        self.set_with_timestamp(key, status, headers, ttl, data, now_secs()).await
+    }
+
+    // Insert keeping the time the object was originally fetched, so an entry
+    // promoted from disk does not outlive its TTL
+    pub async fn set_with_timestamp(&self, key: String, status: StatusCode, headers: HeaderMap, ttl: u64, data: Bytes, timestamp: u64) -> bool {
         if !self.enabled {
             return false;
         }
 
-        let data_size = data.len();
-        
-        // Check if data is too large
-        if data_size > self.max_memory {
-            tracing::warn!("Data size {} exceeds maximum memory limit {}, not caching", data_size, self.max_memory);
+        let retain = retain_until(timestamp, ttl, &headers, self.stale_retention, self.stale_grace())
+            .saturating_sub(now_secs());
+        if retain == 0 {
             return false;
         }
 
-        let mut cache = match self.cache.write() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
-        };
-        
-        // Check memory limit and evict entries if needed
-        let mut current_memory = match self.current_memory.write() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
-        };
-        
-        // Evict entries until we have enough memory
-        while *current_memory + data_size > self.max_memory && !cache.is_empty() {
-            if let Some(key_to_remove) = cache.keys().next().cloned() {
-                if let Some(removed_entry) = cache.remove(&key_to_remove) {
-                    *current_memory = current_memory.saturating_sub(removed_entry.size);
-                    tracing::debug!("Evicted cache entry: {} (size: {})", key_to_remove, removed_entry.size);
-                }
+        let size = data.len();
+        let entry = CacheEntry { data, timestamp, ttl, size, charge: 0, hits: 0, status, headers };
+        match self.backend.set(key.clone(), entry, retain).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                tracing::warn!("Failed to store {} in cache: {}", key, e);
+                false
             }
         }
-        
-        // Check capacity limit
-        if cache.len() >= self.max_capacity && !cache.contains_key(&key) {
-            // Remove oldest entry to make space
-            if let Some(key_to_remove) = cache.keys().next().cloned() {
-                if let Some(removed_entry) = cache.remove(&key_to_remove) {
-                    *current_memory = current_memory.saturating_sub(removed_entry.size);
-                    tracing::debug!("Evicted cache entry due to capacity: {} (size: {})", key_to_remove, removed_entry.size);
-                }
+    }
+
+    pub async fn remove(&self, key: &str) -> bool {
+        let on_disk = self.disk.as_ref().is_some_and(|disk| disk.remove(key));
+        match self.backend.remove(key).await {
+            Ok(removed) => removed || on_disk,
+            Err(e) => {
+                tracing::warn!("Failed to remove {} from cache: {}", key, e);
+                on_disk
             }
         }
-        
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_else(|_| std::time::Duration::from_secs(0))
-            .as_secs();
-        
-        // Update memory usage
-        if let Some(old_entry) = cache.get(&key) {
-            *current_memory = current_memory.saturating_sub(old_entry.size);
+    }
+
+    // Remove every entry whose key matches from both tiers, returning the number of keys removed
+    pub async fn remove_matching(&self, predicate: impl Fn(&str) -> bool + Sync) -> Result<usize> {
+        let mut removed: HashSet<String> = self.backend.remove_matching(&predicate).await?.into_iter().collect();
+        if let Some(disk) = self.disk.as_ref() {
+            removed.extend(disk.remove_matching(|key, _| predicate(key)));
         }
-        *current_memory = current_memory.saturating_add(data_size);
-        
-        cache.insert(key, CacheEntry { data, timestamp, size: data_size });
-        true
+        Ok(removed.len())
     }
 
-    pub fn remove(&self, key: &str) -> bool {
-        let mut cache = match self.cache.write() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
-        };
-        
-        if let Some(removed_entry) = cache.remove(key) {
-            let mut current_memory = match self.current_memory.write() {
-                Ok(guard) => guard,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            *current_memory = current_memory.saturating_sub(removed_entry.size);
-            true
-        } else {
-            false
+    // Summaries of all entries, backend first, then disk most recently used first
+    pub async fn entries(&self) -> Result<Vec<EntrySummary>> {
+        let now = now_secs();
+        let mut summaries = self.backend.entries().await?;
+        if let Some(disk) = self.disk.as_ref() {
+            summaries.extend(disk.entries().into_iter().map(|(key, entry)| EntrySummary {
+                key,
+                tier: "disk",
+                size: entry.size,
+                age: now.saturating_sub(entry.timestamp),
+                ttl: entry.ttl,
+                hits: entry.hits,
+            }));
         }
+        Ok(summaries)
     }
-    
-    pub fn get_memory_usage(&self) -> usize {
-        match self.current_memory.read() {
-            Ok(guard) => *guard,
-            Err(_) => 0,
+
+    // Largest body the backend takes
+    pub fn max_object_size(&self) -> usize {
+        self.backend.max_object_size()
+    }
+
+    pub fn usage(&self) -> BackendUsage {
+        self.backend.usage()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn is_expired(timestamp: u64, ttl: u64) -> bool {
+    now_secs().saturating_sub(timestamp) >= ttl
+}
+
+// Until when an entry is of use: its TTL plus the stale windows that apply.
+// Entries with a validator are kept for revalidation; any entry may be kept
+// for stale serving.
+pub fn retain_until(timestamp: u64, ttl: u64, headers: &HeaderMap, stale_retention: u64, stale_grace: u64) -> u64 {
+    let keep = match has_validators(headers) {
+        true => stale_retention.max(stale_grace),
+        false => stale_grace,
+    };
+    timestamp.saturating_add(ttl).saturating_add(keep)
+}
+
+// Past its TTL and every window it could still be used in
+pub fn is_dead(timestamp: u64, ttl: u64, headers: &HeaderMap, stale_retention: u64, stale_grace: u64) -> bool {
+    now_secs() >= retain_until(timestamp, ttl, headers, stale_retention, stale_grace)
+}
+
+// An expired object is only worth keeping if upstream can confirm it is unchanged
+pub fn has_validators(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED)
+}
+
+// Replace stored headers with the ones sent along with a 304
+pub fn merge_headers(stored: &mut HeaderMap, updated: &HeaderMap) {
+    for name in updated.keys() {
+        stored.remove(name);
+        for value in updated.get_all(name) {
+            stored.append(name.clone(), value.clone());
         }
     }
-    
-    pub fn get_entry_count(&self) -> usize {
-        match self.cache.read() {
-            Ok(guard) => guard.len(),
-            Err(_) => 0,
+}
+
+// Headers as name/value pairs for metadata stored as JSON. Values that are not
+// valid UTF-8 are left out.
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+fn header_map(pairs: Vec<(String, String)>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            headers.append(name, value);
         }
     }
-}
\ No newline at end of file
+    headers
+}
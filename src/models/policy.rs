@@ -0,0 +1,153 @@
+use axum::http::{header, HeaderMap};
+use regex::Regex;
+
+use crate::config::CacheRule;
+use crate::error::{AppError, Result};
+use crate::utils;
+
+struct CompiledRule {
+    regex: Regex,
+    ttl: Option<u64>,
+    no_cache: bool,
+    ignore_cache_control: bool,
+}
+
+// Decides whether and for how long a response is cached
+pub struct CachePolicy {
+    rules: Vec<CompiledRule>,
+    default_ttl: u64,
+}
+
+impl CachePolicy {
+    pub fn new(rules: &[CacheRule], default_ttl: u64) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let regex = match (&rule.class, &rule.pattern) {
+                    (Some(class), None) => utils::url_class(class).cloned().ok_or_else(|| {
+                        AppError::ConfigError(config::ConfigError::Message(format!("unknown URL class {:?} in cache rule", class)))
+                    })?,
+                    (None, Some(pattern)) => Regex::new(pattern)?,
+                    _ => {
+                        return Err(AppError::ConfigError(config::ConfigError::Message(
+                            "a cache rule needs exactly one of class or pattern".to_string(),
+                        )))
+                    }
+                };
+                Ok(CompiledRule {
+                    regex,
+                    ttl: rule.ttl,
+                    no_cache: rule.no_cache,
+                    ignore_cache_control: rule.ignore_cache_control,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { rules, default_ttl })
+    }
+
+    // No rules: everything gets the global TTL unless upstream says otherwise
+    pub fn default_ttl(default_ttl: u64) -> Self {
+        Self { rules: Vec::new(), default_ttl }
+    }
+
+    // Requests that skip the cache entirely
+    pub fn bypass(&self, key: &str) -> bool {
+        self.rule(key).is_some_and(|rule| rule.no_cache)
+    }
+
+    // TTL for a response, or None if it must not be stored. Upstream
+    // Cache-Control wins over the rule TTL unless the rule says otherwise.
+    pub fn ttl(&self, key: &str, headers: &HeaderMap) -> Option<u64> {
+        let rule = self.rule(key);
+        if rule.is_some_and(|rule| rule.no_cache) {
+            return None;
+        }
+        let rule_ttl = rule.and_then(|rule| rule.ttl);
+
+        let ttl = if rule.is_some_and(|rule| rule.ignore_cache_control) {
+            rule_ttl.unwrap_or(self.default_ttl)
+        } else {
+            match cache_control_ttl(headers) {
+                CacheControl::NoStore => return None,
+                CacheControl::MaxAge(max_age) => max_age,
+                CacheControl::Unspecified => rule_ttl.unwrap_or(self.default_ttl),
+            }
+        };
+
+        // Storing something that is stale on arrival only helps if it can be revalidated
+        if ttl == 0 && !super::has_validators(headers) {
+            return None;
+        }
+        Some(ttl)
+    }
+
+    fn rule(&self, key: &str) -> Option<&CompiledRule> {
+        self.rules.iter().find(|rule| rule.regex.is_match(key))
+    }
+}
+
+enum CacheControl {
+    NoStore,
+    MaxAge(u64),
+    Unspecified,
+}
+
+// Read the directives relevant to a shared cache. s-maxage takes precedence
+// over max-age, and no-cache means "store, but revalidate before every use".
+fn cache_control_ttl(headers: &HeaderMap) -> CacheControl {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    let mut no_cache = false;
+    for (name, arg) in directives(headers) {
+        match name.as_str() {
+            "no-store" | "private" => return CacheControl::NoStore,
+            "no-cache" => no_cache = true,
+            "max-age" => max_age = arg.and_then(|v| v.parse().ok()),
+            "s-maxage" => s_maxage = arg.and_then(|v| v.parse().ok()),
+            _ => {}
+        }
+    }
+
+    if no_cache {
+        return CacheControl::MaxAge(0);
+    }
+    match s_maxage.or(max_age) {
+        Some(ttl) => CacheControl::MaxAge(ttl),
+        None => CacheControl::Unspecified,
+    }
+}
+
+// Whether upstream lets an expired copy be served without checking back first
+pub fn allows_stale(headers: &HeaderMap) -> bool {
+    !directives(headers)
+        .any(|(name, _)| matches!(name.as_str(), "must-revalidate" | "proxy-revalidate" | "no-cache"))
+}
+
+// Whether a response is meant for the client that asked for it alone, so a
+// shared cache must not hand it to anyone else (RFC 9111, section 5.2.2)
+pub fn is_private(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::SET_COOKIE)
+        || directives(headers).any(|(name, _)| matches!(name.as_str(), "private" | "no-store"))
+}
+
+// Whether a response to a request with Authorization may be stored by a
+// shared cache (RFC 9111, section 3.5)
+pub fn allows_authorized_storage(headers: &HeaderMap) -> bool {
+    directives(headers).any(|(name, _)| matches!(name.as_str(), "public" | "s-maxage" | "must-revalidate"))
+}
+
+// Cache-Control directives as lowercase names with their unquoted arguments
+fn directives(headers: &HeaderMap) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some((name, arg)) => (name.trim().to_string(), Some(arg.trim().trim_matches('"').to_string())),
+                None => (directive, None),
+            }
+        })
+}
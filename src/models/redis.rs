@@ -0,0 +1,422 @@
+use ::redis::aio::MultiplexedConnection;
+use ::redis::{AsyncCommands, AsyncConnectionConfig, Client, RedisResult};
+use async_trait::async_trait;
+use axum::http::{HeaderMap, StatusCode};
+use bytes::Bytes;
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{header_map, header_pairs, merge_headers, BackendUsage, CacheBackend, CacheEntry, EntrySummary};
+use crate::config::RedisCacheConfig;
+use crate::error::{AppError, Result};
+
+// Keys per MGET or DEL when walking all entries
+const BATCH_SIZE: usize = 100;
+
+// Entry metadata, stored as JSON next to the body
+#[derive(Serialize, Deserialize)]
+struct RedisMeta {
+    key: String,
+    size: u64,
+    timestamp: u64,
+    ttl: u64,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+// Cache shared by every instance using the same Redis server. Each entry is
+// two keys, `<prefix>meta:<key>` and `<prefix>body:<key>`, written together
+// and expiring together once the entry is of no further use. Redis does its
+// own eviction, so size the server's maxmemory and use an LRU policy.
+pub struct RedisBackend {
+    client: Client,
+    // Shared by all requests; dropped when it fails and reopened on next use
+    connection: Mutex<Option<MultiplexedConnection>>,
+    prefix: String,
+    max_object_size: usize,
+    timeout: Duration,
+}
+
+impl RedisBackend {
+    // Connect right away so a wrong URL shows up at startup
+    pub async fn connect(config: &RedisCacheConfig) -> Result<Self> {
+        let backend = Self {
+            client: Client::open(config.url.as_str())?,
+            connection: Mutex::new(None),
+            prefix: config.prefix.clone(),
+            max_object_size: config.max_object_size,
+            timeout: Duration::from_millis(config.timeout),
+        };
+        backend.connection().await?;
+        Ok(backend)
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection> {
+        if let Some(connection) = self.lock().clone() {
+            return Ok(connection);
+        }
+        let config = AsyncConnectionConfig::new()
+            .set_connection_timeout(self.timeout)
+            .set_response_timeout(self.timeout);
+        let connection = self
+            .timed(self.client.get_multiplexed_async_connection_with_config(&config))
+            .await?;
+        *self.lock() = Some(connection.clone());
+        Ok(connection)
+    }
+
+    // Bound every round trip: a stalled or unreachable server must turn into
+    // a cache miss, not a stalled request. The connection is reopened after
+    // anything that may have broken it.
+    async fn timed<T>(&self, request: impl Future<Output = RedisResult<T>>) -> Result<T> {
+        let result = match tokio::time::timeout(self.timeout, request).await {
+            Ok(Ok(value)) => return Ok(value),
+            Ok(Err(e)) if !(e.is_io_error() || e.is_connection_dropped() || e.is_timeout()) => return Err(e.into()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(AppError::CacheError("Redis did not answer in time".to_string())),
+        };
+        *self.lock() = None;
+        result
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<MultiplexedConnection>> {
+        match self.connection.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn meta_key(&self, key: &str) -> String {
+        format!("{}meta:{}", self.prefix, key)
+    }
+
+    fn body_key(&self, key: &str) -> String {
+        format!("{}body:{}", self.prefix, key)
+    }
+
+    // Cache keys of all entries, found through their metadata keys
+    async fn keys(&self) -> Result<Vec<String>> {
+        let mut connection = self.connection().await?;
+        let meta_prefix = self.meta_key("");
+        let mut iter = self
+            .timed(connection.scan_match::<_, String>(format!("{}*", escape_glob(&meta_prefix))))
+            .await?;
+        let mut keys = Vec::new();
+        while let Some(key) = tokio::time::timeout(self.timeout, iter.next_item())
+            .await
+            .map_err(|_| AppError::CacheError("Redis did not answer in time".to_string()))?
+        {
+            if let Some(key) = key.strip_prefix(&meta_prefix) {
+                keys.push(key.to_string());
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl CacheBackend for RedisBackend {
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>> {
+        let mut connection = self.connection().await?;
+        let (meta, body): (Option<Vec<u8>>, Option<Vec<u8>>) =
+            self.timed(connection.mget(&[self.meta_key(key), self.body_key(key)])).await?;
+        let (Some(meta), Some(body)) = (meta, body) else {
+            return Ok(None);
+        };
+        // Written by something else, or the body expired first: treat as a miss
+        let Ok(meta) = serde_json::from_slice::<RedisMeta>(&meta) else {
+            return Ok(None);
+        };
+        let Ok(status) = StatusCode::from_u16(meta.status) else {
+            return Ok(None);
+        };
+        if body.len() as u64 != meta.size {
+            return Ok(None);
+        }
+        Ok(Some(CacheEntry {
+            size: body.len(),
+            data: Bytes::from(body),
+            timestamp: meta.timestamp,
+            ttl: meta.ttl,
+            charge: 0,
+            hits: 0,
+            status,
+            headers: header_map(meta.headers),
+        }))
+    }
+
+    async fn set(&self, key: String, entry: CacheEntry, retain: u64) -> Result<bool> {
+        if entry.size > self.max_object_size {
+            tracing::debug!("Not storing {} in Redis: {} bytes exceeds the {} byte limit",
+                            key, entry.size, self.max_object_size);
+            return Ok(false);
+        }
+        let meta = RedisMeta {
+            key: key.clone(),
+            size: entry.size as u64,
+            timestamp: entry.timestamp,
+            ttl: entry.ttl,
+            status: entry.status.as_u16(),
+            headers: header_pairs(&entry.headers),
+        };
+        let meta = serde_json::to_vec(&meta).map_err(|e| AppError::CacheError(e.to_string()))?;
+
+        let mut connection = self.connection().await?;
+        let mut pipe = ::redis::pipe();
+        pipe.atomic()
+            .set_ex(self.body_key(&key), entry.data.as_ref(), retain)
+            .ignore()
+            .set_ex(self.meta_key(&key), meta, retain)
+            .ignore();
+        self.timed(pipe.query_async::<()>(&mut connection)).await?;
+        Ok(true)
+    }
+
+    async fn refresh(&self, key: &str, headers: &HeaderMap, timestamp: u64, ttl: u64, retain: u64) -> Result<()> {
+        let mut connection = self.connection().await?;
+        let meta: Option<Vec<u8>> = self.timed(connection.get(self.meta_key(key))).await?;
+        let Some(mut meta) = meta.and_then(|meta| serde_json::from_slice::<RedisMeta>(&meta).ok()) else {
+            return Ok(());
+        };
+        let mut stored = header_map(std::mem::take(&mut meta.headers));
+        merge_headers(&mut stored, headers);
+        meta.headers = header_pairs(&stored);
+        meta.timestamp = timestamp;
+        meta.ttl = ttl;
+        let meta = serde_json::to_vec(&meta).map_err(|e| AppError::CacheError(e.to_string()))?;
+
+        let mut pipe = ::redis::pipe();
+        pipe.atomic()
+            .set_ex(self.meta_key(key), meta, retain)
+            .ignore()
+            .expire(self.body_key(key), retain as i64)
+            .ignore();
+        self.timed(pipe.query_async::<()>(&mut connection)).await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<bool> {
+        let mut connection = self.connection().await?;
+        let removed: usize = self.timed(connection.del(&[self.meta_key(key), self.body_key(key)])).await?;
+        Ok(removed > 0)
+    }
+
+    async fn remove_matching(&self, predicate: &(dyn for<'k> Fn(&'k str) -> bool + Sync)) -> Result<Vec<String>> {
+        let matching: Vec<String> = self.keys().await?.into_iter().filter(|key| predicate(key)).collect();
+        let mut connection = self.connection().await?;
+        for batch in matching.chunks(BATCH_SIZE) {
+            let keys: Vec<String> = batch
+                .iter()
+                .flat_map(|key| [self.meta_key(key), self.body_key(key)])
+                .collect();
+            self.timed(connection.del::<_, ()>(keys)).await?;
+        }
+        Ok(matching)
+    }
+
+    // Entries expire on the server
+    async fn sweep(&self, _is_dead: &(dyn for<'e> Fn(&'e CacheEntry) -> bool + Sync)) -> Result<usize> {
+        Ok(0)
+    }
+
+    // Redis does not report recency or hits per key, so entries come in no
+    // particular order and with a hit count of zero
+    async fn entries(&self) -> Result<Vec<EntrySummary>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let keys = self.keys().await?;
+        let mut connection = self.connection().await?;
+        let mut summaries = Vec::with_capacity(keys.len());
+        for batch in keys.chunks(BATCH_SIZE) {
+            let meta_keys: Vec<String> = batch.iter().map(|key| self.meta_key(key)).collect();
+            let metas: Vec<Option<Vec<u8>>> = self.timed(connection.mget(meta_keys)).await?;
+            summaries.extend(
+                metas
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|meta| serde_json::from_slice::<RedisMeta>(&meta).ok())
+                    .map(|meta| EntrySummary {
+                        key: meta.key,
+                        tier: "redis",
+                        size: meta.size,
+                        age: now.saturating_sub(meta.timestamp),
+                        ttl: meta.ttl,
+                        hits: 0,
+                    }),
+            );
+        }
+        Ok(summaries)
+    }
+
+    fn max_object_size(&self) -> usize {
+        self.max_object_size
+    }
+
+    // Sizes are up to the server
+    fn usage(&self) -> BackendUsage {
+        BackendUsage {
+            kind: "redis",
+            entries: None,
+            max_entries: None,
+            bytes: None,
+            max_bytes: None,
+        }
+    }
+}
+
+// Match a prefix literally in a SCAN pattern
+fn escape_glob(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Need a server to talk to, so they only run on request:
+//   REDIS_URL=redis://127.0.0.1:6379 cargo test redis -- --ignored
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    // None when no server is configured. Each backend gets a prefix of its
+    // own so runs do not see each other's keys.
+    async fn connect(max_object_size: usize) -> Option<RedisBackend> {
+        let Ok(url) = std::env::var("REDIS_URL") else {
+            eprintln!("REDIS_URL is not set, skipping");
+            return None;
+        };
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        let config = RedisCacheConfig {
+            url,
+            prefix: format!("proxy-test:{}:{}:", std::process::id(), nanos),
+            max_object_size,
+            timeout: 2000,
+        };
+        Some(RedisBackend::connect(&config).await.unwrap())
+    }
+
+    fn entry(body: &'static [u8]) -> CacheEntry {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/octet-stream"));
+        headers.insert("etag", HeaderValue::from_static("\"v1\""));
+        CacheEntry {
+            data: Bytes::from_static(body),
+            timestamp: 1_700_000_000,
+            ttl: 60,
+            size: body.len(),
+            charge: 0,
+            hits: 0,
+            status: StatusCode::OK,
+            headers,
+        }
+    }
+
+    async fn clear(backend: &RedisBackend) {
+        backend.remove_matching(&|_| true).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn set_then_get_round_trips() {
+        let Some(backend) = connect(1 << 20).await else { return };
+
+        assert!(backend.get("missing").await.unwrap().is_none());
+        assert!(backend.set("key".to_string(), entry(b"hello"), 300).await.unwrap());
+        let stored = backend.get("key").await.unwrap().unwrap();
+        assert_eq!(stored.data, Bytes::from_static(b"hello"));
+        assert_eq!(stored.size, 5);
+        assert_eq!(stored.timestamp, 1_700_000_000);
+        assert_eq!(stored.ttl, 60);
+        assert_eq!(stored.status, StatusCode::OK);
+        assert_eq!(stored.headers.get("etag").unwrap(), "\"v1\"");
+        assert_eq!(stored.headers.get("content-type").unwrap(), "application/octet-stream");
+
+        assert!(backend.remove("key").await.unwrap());
+        assert!(backend.get("key").await.unwrap().is_none());
+        clear(&backend).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn both_keys_expire_after_the_retain_time() {
+        let Some(backend) = connect(1 << 20).await else { return };
+
+        backend.set("key".to_string(), entry(b"hello"), 120).await.unwrap();
+        let mut connection = backend.connection().await.unwrap();
+        for key in [backend.meta_key("key"), backend.body_key("key")] {
+            let ttl: i64 = connection.ttl(&key).await.unwrap();
+            assert!((1..=120).contains(&ttl), "{} expires in {}", key, ttl);
+        }
+
+        // A refresh pushes the expiry of both keys out again
+        backend.refresh("key", &HeaderMap::new(), 1_700_000_100, 90, 600).await.unwrap();
+        for key in [backend.meta_key("key"), backend.body_key("key")] {
+            let ttl: i64 = connection.ttl(&key).await.unwrap();
+            assert!((121..=600).contains(&ttl), "{} expires in {}", key, ttl);
+        }
+        let stored = backend.get("key").await.unwrap().unwrap();
+        assert_eq!((stored.timestamp, stored.ttl), (1_700_000_100, 90));
+        clear(&backend).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn objects_over_the_size_cap_are_not_stored() {
+        let Some(backend) = connect(8).await else { return };
+
+        assert!(!backend.set("large".to_string(), entry(b"too large to store"), 300).await.unwrap());
+        assert!(backend.get("large").await.unwrap().is_none());
+        let mut connection = backend.connection().await.unwrap();
+        let exists: usize = connection.exists(&[backend.meta_key("large"), backend.body_key("large")]).await.unwrap();
+        assert_eq!(exists, 0);
+
+        assert!(backend.set("small".to_string(), entry(b"fits"), 300).await.unwrap());
+        assert!(backend.get("small").await.unwrap().is_some());
+        clear(&backend).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn remove_matching_scans_every_entry_under_the_prefix() {
+        let Some(backend) = connect(1 << 20).await else { return };
+        let Some(other) = connect(1 << 20).await else { return };
+
+        // More than one batch, so the scan and the deletes both page
+        let count = BATCH_SIZE * 2 + 10;
+        for i in 0..count {
+            let key = if i % 2 == 0 { format!("lfs/{}", i) } else { format!("git/{}", i) };
+            backend.set(key, entry(b"body"), 300).await.unwrap();
+        }
+        other.set("lfs/elsewhere".to_string(), entry(b"body"), 300).await.unwrap();
+
+        let mut removed = backend.remove_matching(&|key| key.starts_with("lfs/")).await.unwrap();
+        removed.sort();
+        let mut expected: Vec<String> = (0..count).step_by(2).map(|i| format!("lfs/{}", i)).collect();
+        expected.sort();
+        assert_eq!(removed, expected);
+
+        let mut left = backend.keys().await.unwrap();
+        left.sort();
+        let mut expected: Vec<String> = (1..count).step_by(2).map(|i| format!("git/{}", i)).collect();
+        expected.sort();
+        assert_eq!(left, expected);
+        assert!(backend.get("lfs/0").await.unwrap().is_none());
+        assert!(backend.get("git/1").await.unwrap().is_some());
+
+        // Another prefix on the same server is left alone
+        assert!(other.get("lfs/elsewhere").await.unwrap().is_some());
+
+        clear(&backend).await;
+        clear(&other).await;
+        assert!(backend.keys().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+const DEPTH: usize = 4;
+const MAX_COUNT: u8 = 15;
+
+// Count-min sketch of recent access frequencies for TinyLFU admission. Counters
+// saturate at 15 and are halved once `sample_size` accesses have been recorded,
+// so old popularity fades out.
+pub struct FrequencySketch {
+    table: Vec<u8>,
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    pub fn new(capacity: usize) -> Self {
+        let width = capacity.max(16).next_power_of_two();
+        Self {
+            table: vec![0; width * DEPTH],
+            mask: width - 1,
+            additions: 0,
+            sample_size: width * 10,
+        }
+    }
+
+    pub fn increment(&mut self, key: &str) {
+        let hash = hash_key(key);
+        let mut added = false;
+        for row in 0..DEPTH {
+            let slot = self.slot(hash, row);
+            if self.table[slot] < MAX_COUNT {
+                self.table[slot] += 1;
+                added = true;
+            }
+        }
+        if added {
+            self.additions += 1;
+            if self.additions >= self.sample_size {
+                self.age();
+            }
+        }
+    }
+
+    pub fn frequency(&self, key: &str) -> u8 {
+        let hash = hash_key(key);
+        (0..DEPTH)
+            .map(|row| self.table[self.slot(hash, row)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn slot(&self, hash: u64, row: usize) -> usize {
+        // Derive one index per row from the two halves of the hash
+        let h1 = hash as u32 as usize;
+        let h2 = (hash >> 32) as usize;
+        row * (self.mask + 1) + (h1.wrapping_add(row.wrapping_mul(h2)) & self.mask)
+    }
+
+    fn age(&mut self) {
+        for counter in self.table.iter_mut() {
+            *counter >>= 1;
+        }
+        self.additions /= 2;
+    }
+}
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_accesses_per_key() {
+        let mut sketch = FrequencySketch::new(64);
+        for _ in 0..5 {
+            sketch.increment("hot");
+        }
+        sketch.increment("warm");
+        assert_eq!(sketch.frequency("hot"), 5);
+        assert_eq!(sketch.frequency("warm"), 1);
+        assert_eq!(sketch.frequency("cold"), 0);
+    }
+
+    #[test]
+    fn counters_saturate() {
+        let mut sketch = FrequencySketch::new(64);
+        for _ in 0..100 {
+            sketch.increment("hot");
+        }
+        assert_eq!(sketch.frequency("hot"), MAX_COUNT);
+    }
+
+    #[test]
+    fn counts_are_halved_after_a_sample() {
+        let mut sketch = FrequencySketch::new(16);
+        for _ in 0..8 {
+            sketch.increment("old");
+        }
+        // Fill the sample up to one short of the limit with keys seen once
+        let mut i = 0;
+        while sketch.additions < sketch.sample_size - 1 {
+            sketch.increment(&format!("key-{}", i));
+            i += 1;
+        }
+        let before = sketch.frequency("old");
+        assert!(before >= 8);
+
+        sketch.increment("last");
+        let after = sketch.frequency("old");
+        assert!(after == before / 2 || after == before.div_ceil(2), "{} became {}", before, after);
+        assert_eq!(sketch.additions, sketch.sample_size / 2);
+    }
+}
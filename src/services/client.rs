@@ -0,0 +1,295 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use reqwest::redirect::Policy;
+use tracing::debug;
+
+use crate::{
+    config::{Config, HttpVersion},
+    error::{AppError, Result},
+    utils::{self, GitService},
+};
+
+// One upstream client per service, so each can leave through its own egress proxy
+#[derive(Clone)]
+pub struct UpstreamClients {
+    github: reqwest::Client,
+    gitlab: reqwest::Client,
+    bitbucket: reqwest::Client,
+}
+
+impl UpstreamClients {
+    pub fn new(config: &Config) -> Result<Self> {
+        let egress = &config.egress;
+        Ok(Self {
+            github: build_client(config, egress.github.as_deref().or(egress.proxy.as_deref()))?,
+            gitlab: build_client(config, egress.gitlab.as_deref().or(egress.proxy.as_deref()))?,
+            bitbucket: build_client(config, egress.bitbucket.as_deref().or(egress.proxy.as_deref()))?,
+        })
+    }
+
+    // Pick the client for the service a URL belongs to
+    pub fn for_url(&self, url: &str) -> &reqwest::Client {
+        match utils::git_service(url) {
+            GitService::GitHub => &self.github,
+            GitService::GitLab => &self.gitlab,
+            GitService::Bitbucket => &self.bitbucket,
+        }
+    }
+}
+
+// Build an upstream HTTP client from the [upstream] settings. `proxy` is the
+// egress proxy URL, or "direct" to bypass proxies including the environment's.
+pub fn build_client(config: &Config, proxy: Option<&str>) -> Result<reqwest::Client> {
+    let upstream = &config.upstream;
+    let mut builder = reqwest::Client::builder()
+        .redirect(redirect_policy(config))
+        .pool_max_idle_per_host(upstream.pool_max_idle_per_host)
+        .tcp_nodelay(upstream.tcp_nodelay);
+
+    if upstream.connect_timeout > 0 {
+        builder = builder.connect_timeout(Duration::from_secs(upstream.connect_timeout));
+    }
+    if upstream.read_timeout > 0 {
+        builder = builder.read_timeout(Duration::from_secs(upstream.read_timeout));
+    }
+    builder = builder.pool_idle_timeout(
+        (upstream.pool_idle_timeout > 0).then(|| Duration::from_secs(upstream.pool_idle_timeout)),
+    );
+    builder = builder.tcp_keepalive(
+        (upstream.tcp_keepalive > 0).then(|| Duration::from_secs(upstream.tcp_keepalive)),
+    );
+    builder = match upstream.http_version {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::PriorKnowledge => builder.http2_prior_knowledge(),
+    };
+    if let Some(user_agent) = &upstream.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+
+    match proxy {
+        Some("direct") => builder = builder.no_proxy(),
+        Some(url) => {
+            // Fail on a malformed URL now rather than on the first request
+            reqwest::Proxy::all(url).map_err(AppError::ReqwestError)?;
+            // Credentials in the URL are used for Basic or SOCKS5 authentication.
+            // reqwest's own no_proxy skips the proxy but still sends its
+            // Proxy-Authorization, so bypassed hosts are decided here.
+            let (url, bypass) = (url.to_string(), NoProxy::new(&config.egress.no_proxy));
+            let proxy = reqwest::Proxy::custom(move |target| {
+                (!bypass.contains(target.host_str()?)).then(|| url.clone())
+            });
+            builder = builder.proxy(proxy);
+        }
+        None => {}
+    }
+
+    builder.build().map_err(AppError::ReqwestError)
+}
+
+// Hosts reached without the egress proxy: addresses and CIDR blocks, domains
+// with their subdomains, or "*" for everything
+struct NoProxy {
+    networks: Vec<IpNet>,
+    domains: Vec<String>,
+}
+
+impl NoProxy {
+    fn new(entries: &[String]) -> Self {
+        let mut no_proxy = Self { networks: Vec::new(), domains: Vec::new() };
+        for entry in entries.iter().map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            match entry.parse::<IpNet>().or_else(|_| entry.parse::<IpAddr>().map(IpNet::from)) {
+                Ok(network) => no_proxy.networks.push(network),
+                Err(_) => no_proxy.domains.push(entry.trim_start_matches('.').to_ascii_lowercase()),
+            }
+        }
+        no_proxy
+    }
+
+    fn contains(&self, host: &str) -> bool {
+        // IPv6 hosts come bracketed in URLs
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self.networks.iter().any(|network| network.contains(&ip.to_canonical()));
+        }
+        let host = host.to_ascii_lowercase();
+        self.domains.iter().any(|domain| {
+            domain == "*"
+                || *domain == host
+                || host.strip_suffix(domain.as_str()).is_some_and(|rest| rest.ends_with('.'))
+        })
+    }
+}
+
+// Map a reqwest failure to the matching AppError so each timeout is reported as such
+pub fn upstream_error(e: reqwest::Error) -> AppError {
+    if e.is_redirect() {
+        AppError::RedirectError(e.to_string())
+    } else if e.is_timeout() && e.is_connect() {
+        AppError::UpstreamConnectTimeout(e.to_string())
+    } else if e.is_timeout() {
+        AppError::UpstreamReadTimeout(e.to_string())
+    } else {
+        AppError::ReqwestError(e)
+    }
+}
+
+// Follow redirects only while they stay on URLs the proxy itself would serve;
+// anything else is handed back to the client with its Location rewritten.
+fn redirect_policy(config: &Config) -> Policy {
+    let max_hops = config.redirect.max_hops;
+    if max_hops == 0 {
+        return Policy::none();
+    }
+
+    let config = config.clone();
+    Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_hops {
+            return attempt.error(format!("exceeded {} redirect hops", max_hops));
+        }
+        if utils::is_supported_url(attempt.url().as_str(), &config) {
+            debug!("Following upstream redirect to: {}", attempt.url());
+            attempt.follow()
+        } else {
+            debug!("Not following redirect to non-allowlisted URL: {}", attempt.url());
+            attempt.stop()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // "user:secret", as Basic credentials
+    const CREDENTIALS: &str = "Basic dXNlcjpzZWNyZXQ=";
+
+    // Request line and Proxy-Authorization of each request a server received
+    type Seen = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    // A server answering every request with `body` and recording its head.
+    // As a proxy it answers absolute-form requests itself, and accepts
+    // CONNECT without tunnelling anywhere.
+    async fn serve(body: &'static str) -> (SocketAddr, Seen) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seen = Seen::default();
+        let log = seen.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let log = log.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let head = String::from_utf8_lossy(&head).into_owned();
+                    let mut lines = head.lines();
+                    let line = lines.next().unwrap_or_default().to_string();
+                    let auth = lines
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("proxy-authorization"))
+                        .map(|(_, value)| value.trim().to_string());
+                    let connect = line.starts_with("CONNECT ");
+                    log.lock().unwrap().push((line, auth));
+                    let response = match connect {
+                        true => "HTTP/1.1 200 Connection Established\r\n\r\n".to_string(),
+                        false => format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        ),
+                    };
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (addr, seen)
+    }
+
+    fn config(proxy: SocketAddr, no_proxy: &[&str]) -> Config {
+        let mut config = Config::default();
+        config.egress.proxy = Some(format!("http://user:secret@{}", proxy));
+        config.egress.no_proxy = no_proxy.iter().map(|host| host.to_string()).collect();
+        config
+    }
+
+    async fn get(client: &reqwest::Client, url: &str) -> String {
+        client.get(url).send().await.unwrap().text().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn proxied_requests_carry_credentials() {
+        let (proxy, seen) = serve("proxy").await;
+        let clients = UpstreamClients::new(&config(proxy, &[])).unwrap();
+
+        let client = clients.for_url("http://github.com/owner/repo/raw/main/file");
+        assert_eq!(get(client, "http://github.com/owner/repo/raw/main/file").await, "proxy");
+        // TLS through the tunnel cannot succeed; the CONNECT is what counts
+        assert!(client.get("https://gitlab.com/owner/repo").send().await.is_err());
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0].0, "GET http://github.com/owner/repo/raw/main/file HTTP/1.1");
+        assert_eq!(seen[1].0, "CONNECT gitlab.com:443 HTTP/1.1");
+        assert!(seen.iter().all(|(_, auth)| auth.as_deref() == Some(CREDENTIALS)));
+    }
+
+    #[tokio::test]
+    async fn no_proxy_hosts_bypass_proxy() {
+        let (proxy, seen) = serve("proxy").await;
+        let (origin, origin_seen) = serve("origin").await;
+        let clients = UpstreamClients::new(&config(proxy, &["127.0.0.1"])).unwrap();
+
+        let client = clients.for_url("https://github.com/owner/repo");
+        assert_eq!(get(client, &format!("http://{}/file", origin)).await, "origin");
+        assert_eq!(get(client, "http://github.com/owner/repo").await, "proxy");
+
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        let origin_seen = origin_seen.lock().unwrap();
+        assert_eq!(origin_seen[..], [("GET /file HTTP/1.1".to_string(), None)]);
+    }
+
+    #[test]
+    fn no_proxy_matches_hosts_domains_and_networks() {
+        let entries = ["objects.githubusercontent.com", ".gitlab.com", "10.0.0.0/8", "::1"];
+        let no_proxy = NoProxy::new(&entries.map(String::from));
+
+        assert!(no_proxy.contains("objects.githubusercontent.com"));
+        assert!(no_proxy.contains("a.objects.githubusercontent.com"));
+        assert!(no_proxy.contains("GitLab.com"));
+        assert!(no_proxy.contains("registry.gitlab.com"));
+        assert!(no_proxy.contains("10.1.2.3"));
+        assert!(no_proxy.contains("[::1]"));
+        assert!(!no_proxy.contains("githubusercontent.com"));
+        assert!(!no_proxy.contains("notgitlab.com"));
+        assert!(!no_proxy.contains("11.0.0.1"));
+        assert!(NoProxy::new(&["*".to_string()]).contains("github.com"));
+    }
+
+    #[tokio::test]
+    async fn direct_bypasses_proxy() {
+        let (proxy, seen) = serve("proxy").await;
+        let (origin, origin_seen) = serve("origin").await;
+        let mut config = config(proxy, &[]);
+        config.egress.gitlab = Some("direct".to_string());
+        let clients = UpstreamClients::new(&config).unwrap();
+
+        let direct = clients.for_url("https://gitlab.com/owner/repo");
+        assert_eq!(get(direct, &format!("http://{}/file", origin)).await, "origin");
+        let proxied = clients.for_url("https://github.com/owner/repo");
+        assert_eq!(get(proxied, &format!("http://{}/file", origin)).await, "proxy");
+
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        assert_eq!(origin_seen.lock().unwrap().len(), 1);
+    }
+}
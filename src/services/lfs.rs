@@ -0,0 +1,59 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderValue},
+    response::Response,
+};
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::{
+    config::Config,
+    error::{AppError, Result},
+    utils,
+};
+
+// Batch responses are small JSON documents; anything bigger is not one
+const MAX_BATCH_RESPONSE: usize = 16 * 1024 * 1024;
+
+// Rewrite the download hrefs of an LFS batch response so the LFS client
+// fetches every object through the proxy instead of from the storage host
+pub async fn rewrite_lfs_batch(response: Response, proxy_base: &str, config: &Config) -> Result<Response> {
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_BATCH_RESPONSE)
+        .await
+        .map_err(|e| AppError::InvalidRequest(format!("Failed to read LFS batch response: {}", e)))?;
+
+    let mut batch: Value = match serde_json::from_slice(&bytes) {
+        Ok(batch) => batch,
+        Err(e) => {
+            debug!("LFS batch response is not JSON ({}), passing through", e);
+            return Ok(Response::from_parts(parts, Body::from(bytes)));
+        }
+    };
+
+    let mut rewritten = 0;
+    if let Some(objects) = batch.get_mut("objects").and_then(Value::as_array_mut) {
+        for object in objects {
+            let Some(href) = object.pointer_mut("/actions/download/href") else {
+                continue;
+            };
+            let Some(url) = href.as_str().filter(|url| utils::is_supported_url(url, config)) else {
+                continue;
+            };
+            *href = Value::String(format!("{}/{}", proxy_base, url));
+            rewritten += 1;
+        }
+    }
+    info!("Rewrote {} LFS download hrefs to {}", rewritten, proxy_base);
+
+    let body = serde_json::to_vec(&batch)
+        .map_err(|e| AppError::InvalidRequest(format!("Failed to encode LFS batch response: {}", e)))?;
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
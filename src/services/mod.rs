@@ -1,58 +1,111 @@
 use axum::{
-    body::Body,
-    http::{header, Uri},
+    body::{Body, HttpBody},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
     response::Response,
 };
-use reqwest;
-use sync_wrapper::SyncStream;
-use futures::StreamExt;
 use bytes::Bytes;
-use tracing::info;
-use crate::error::{AppError, Result};
-
-// Helper function to collect bytes from stream
-pub async fn collect_bytes(
-    mut stream: impl futures::Stream<Item = std::result::Result<Bytes, reqwest::Error>> + Unpin,
-) -> Result<Vec<u8>> {
-    let mut bytes = Vec::new();
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk.map_err(AppError::ReqwestError)?;
-        bytes.extend_from_slice(&chunk);
-    }
-    Ok(bytes)
-}
+use futures::{Stream, StreamExt};
+use std::time::Duration;
+use sync_wrapper::SyncStream;
+use tokio::time::{timeout_at, Instant};
+use tracing::{info, warn};
+use crate::{
+    config::{Config, SizeLimitAction},
+    error::{AppError, Result},
+    handlers,
+    utils,
+};
+
+mod client;
+mod lfs;
+mod retry;
+pub use client::*;
+pub use lfs::*;
+pub use retry::*;
 
 // Handle proxy requests
 pub async fn handle_proxy(
     mut req: axum::extract::Request,
     client: &reqwest::Client,
     path_query: String,
+    config: &Config,
 ) -> Result<Response> {
     // Remove HOST header
     req.headers_mut().remove(header::HOST);
+    // A configured User-Agent replaces the client's own
+    if config.upstream.user_agent.is_some() {
+        req.headers_mut().remove(header::USER_AGENT);
+    }
+    // Everything else, including Git-Protocol and Content-Encoding on git
+    // request bodies, is end-to-end and goes upstream untouched
+    strip_hop_by_hop(req.headers_mut());
 
-    // Create new URI (clone path_query to avoid move)
-    let new_uri = Uri::try_from(path_query.clone())
+    let url = reqwest::Url::parse(&path_query)
         .map_err(|_| AppError::InvalidRequest("Invalid URI".to_string()))?;
+    let (parts, body) = req.into_parts();
+    let method = parts.method;
+    let headers = parts.headers;
 
-    *req.uri_mut() = new_uri;
-
-    // Convert axum request to reqwest request
-    let axum_request = req.map(|body| reqwest::Body::wrap_stream(SyncStream::new(body.into_data_stream())));
-    let reqwest_request = reqwest::Request::try_from(axum_request)
-        .map_err(|_| AppError::InvalidRequest("Failed to convert request".to_string()))?;
+    // GET/HEAD without a body can be replayed, so transient failures are retried
+    let idempotent = (method == Method::GET || method == Method::HEAD)
+        && body.size_hint().exact() == Some(0);
 
     // Execute request
     info!("Making HTTP request to: {}", path_query);
-    let response = client.execute(reqwest_request).await
-        .map_err(AppError::ReqwestError)?;
+    let deadline = (config.upstream.total_timeout > 0)
+        .then(|| Instant::now() + Duration::from_secs(config.upstream.total_timeout));
+    let exchange = async {
+        if idempotent && config.retry.enabled {
+            execute_with_retry(client, &config.retry, || {
+                build_request(&method, &url, &headers, reqwest::Body::from(Bytes::new()))
+            })
+            .await
+        } else {
+            let body = reqwest::Body::wrap_stream(SyncStream::new(body.into_data_stream()));
+            client.execute(build_request(&method, &url, &headers, body)).await
+        }
+    };
+    let response = match deadline {
+        Some(deadline) => timeout_at(deadline, exchange)
+            .await
+            .map_err(|_| AppError::UpstreamTimeout(format!(
+                "no response from {} within {}s", path_query, config.upstream.total_timeout
+            )))?,
+        None => exchange.await,
+    }
+    .map_err(upstream_error)?;
 
-    // Get the response bytes and headers before consuming the response
-    let headers = response.headers().clone();
+    let mut headers = response.headers().clone();
+    strip_hop_by_hop(&mut headers);
     let status = response.status();
-    
-    info!("Received upstream response, status: {}", status);
-    let response_bytes = collect_bytes(response.bytes_stream()).await?;
+    info!("Received upstream response from {}, status: {}", response.url(), status);
+
+    if status.is_redirection() {
+        rewrite_location(&mut headers, response.url(), config);
+    }
+
+    let max_size = config.limits.max_size;
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(length) = content_length.filter(|&len| max_size > 0 && len > max_size) {
+        warn!("Upstream response {} is {} bytes, over the {} byte limit", response.url(), length, max_size);
+        return match config.limits.action {
+            SizeLimitAction::Redirect => {
+                let mut res = handlers::handle_redirect_to(response.url().as_str())?;
+                if method != Method::GET && method != Method::HEAD {
+                    // Keep the method and body on the retry against the origin
+                    *res.status_mut() = StatusCode::TEMPORARY_REDIRECT;
+                }
+                Ok(res)
+            }
+            SizeLimitAction::Reject => Err(AppError::PayloadTooLarge(format!(
+                "{} bytes exceeds the {} byte limit",
+                length, max_size
+            ))),
+        };
+    }
 
     // Build response
     let mut builder = Response::builder().status(status);
@@ -60,8 +113,118 @@ pub async fn handle_proxy(
         *builder_headers = headers;
     }
 
-    let response = builder.body(Body::from(response_bytes))
+    // Stream the upstream body straight through. Hyper only polls the stream as
+    // fast as the client reads it, and dropping the body when the client goes
+    // away drops the upstream response and closes that connection too.
+    let stream = response.bytes_stream().map(|chunk| chunk.map_err(std::io::Error::other));
+    let stream = match deadline {
+        Some(deadline) => deadline_stream(stream, deadline).boxed(),
+        None => stream.boxed(),
+    };
+    let body = if max_size > 0 && content_length.is_none() {
+        Body::from_stream(limit_stream(stream, max_size))
+    } else {
+        Body::from_stream(stream)
+    };
+    let response = builder.body(body)
         .map_err(|e| AppError::CacheError(e.to_string()))?;
-        
+
     Ok(response)
-}
\ No newline at end of file
+}
+
+// Build an upstream request carrying the client's (already filtered) headers
+fn build_request(method: &Method, url: &reqwest::Url, headers: &HeaderMap, body: reqwest::Body) -> reqwest::Request {
+    let mut request = reqwest::Request::new(method.clone(), url.clone());
+    *request.headers_mut() = headers.clone();
+    *request.body_mut() = Some(body);
+    request
+}
+
+// End the body with an error once the total upstream time budget runs out
+fn deadline_stream(
+    stream: impl Stream<Item = std::io::Result<Bytes>> + Send + 'static,
+    deadline: Instant,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    futures::stream::unfold(Some(Box::pin(stream)), move |stream| async move {
+        let mut stream = stream?;
+        match timeout_at(deadline, stream.next()).await {
+            Ok(Some(chunk)) => Some((chunk, Some(stream))),
+            Ok(None) => None,
+            Err(_) => {
+                warn!("Upstream total timeout reached while streaming body");
+                Some((Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "upstream total timeout")), None))
+            }
+        }
+    })
+}
+
+// Remove hop-by-hop headers (RFC 9110 section 7.6.1), including any listed in
+// Connection. Framing is redone by hyper on each side of the proxy.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in listed {
+        headers.remove(name.as_str());
+    }
+
+    for name in [
+        header::CONNECTION,
+        header::PROXY_AUTHENTICATE,
+        header::PROXY_AUTHORIZATION,
+        header::TE,
+        header::TRAILER,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+        header::EXPECT,
+    ] {
+        headers.remove(name);
+    }
+    headers.remove("keep-alive");
+    headers.remove("proxy-connection");
+}
+
+// Point a redirect Location back at the proxy so the client never has to reach
+// the origin directly. Relative locations are resolved against the upstream URL.
+fn rewrite_location(headers: &mut HeaderMap, base: &reqwest::Url, config: &Config) {
+    let Some(location) = headers.get(header::LOCATION).and_then(|v| v.to_str().ok()) else {
+        return;
+    };
+    let Ok(target) = base.join(location) else {
+        return;
+    };
+
+    let rewritten = if utils::is_supported_url(target.as_str(), config) {
+        format!("/{}", target)
+    } else {
+        target.to_string()
+    };
+
+    info!("Rewriting redirect location {} -> {}", location, rewritten);
+    if let Ok(value) = HeaderValue::from_str(&rewritten) {
+        headers.insert(header::LOCATION, value);
+    }
+}
+
+// Abort a body of unknown length once it grows past `max_size`. The error ends
+// the response mid-stream, which drops the upstream connection as well.
+fn limit_stream(
+    stream: impl Stream<Item = std::io::Result<Bytes>>,
+    max_size: u64,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let mut received = 0u64;
+    stream.map(move |chunk| {
+        let chunk = chunk?;
+        received += chunk.len() as u64;
+        if received > max_size {
+            warn!("Aborting upstream stream after {} bytes, over the {} byte limit", received, max_size);
+            return Err(std::io::Error::other("response exceeds size limit"));
+        }
+        Ok(chunk)
+    })
+}
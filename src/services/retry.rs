@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::config::RetryConfig;
+
+// Process-wide retry counters
+pub struct RetryStats {
+    // Extra attempts made after a retryable failure
+    pub retries: AtomicU64,
+    // Requests that still failed once retries or the time budget ran out
+    pub exhausted: AtomicU64,
+}
+
+pub static RETRY_STATS: RetryStats = RetryStats {
+    retries: AtomicU64::new(0),
+    exhausted: AtomicU64::new(0),
+};
+
+// Execute an idempotent request, retrying transport errors and retryable
+// statuses with jittered exponential backoff. `make_request` builds a fresh
+// request for every attempt. Only the final outcome is returned, so nothing
+// reaches the client until retrying is over.
+pub async fn execute_with_retry(
+    client: &reqwest::Client,
+    retry: &RetryConfig,
+    mut make_request: impl FnMut() -> reqwest::Request,
+) -> reqwest::Result<reqwest::Response> {
+    let budget = Instant::now() + Duration::from_millis(retry.budget_ms);
+    let mut attempt = 0;
+
+    loop {
+        let request = make_request();
+        let url = request.url().clone();
+        let outcome = client.execute(request).await;
+
+        let reason = match &outcome {
+            Ok(response) if retry.statuses.contains(&response.status().as_u16()) => {
+                format!("status {}", response.status())
+            }
+            Err(e) if is_retryable_error(e) => e.to_string(),
+            _ => return outcome,
+        };
+
+        let delay = backoff(retry, attempt);
+        if attempt >= retry.max_retries || Instant::now() + delay > budget {
+            warn!("Giving up on {} after {} retries: {}", url, attempt, reason);
+            RETRY_STATS.exhausted.fetch_add(1, Ordering::Relaxed);
+            return outcome;
+        }
+
+        attempt += 1;
+        RETRY_STATS.retries.fetch_add(1, Ordering::Relaxed);
+        warn!("Retrying {} in {:?} (attempt {}/{}): {}", url, delay, attempt, retry.max_retries, reason);
+        // Release the failed response's connection before waiting
+        drop(outcome);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Failures that happened before any response arrived: connect errors,
+// timeouts and resets while sending
+fn is_retryable_error(e: &reqwest::Error) -> bool {
+    !e.is_redirect() && !e.is_builder() && (e.is_connect() || e.is_timeout() || e.is_request())
+}
+
+// Full jitter: a random delay between zero and the capped exponential step
+fn backoff(retry: &RetryConfig, attempt: u32) -> Duration {
+    let step = retry
+        .base_delay_ms
+        .saturating_mul(1u64 << attempt.min(20))
+        .min(retry.max_delay_ms);
+    Duration::from_millis(rand::thread_rng().gen_range(0..=step))
+}
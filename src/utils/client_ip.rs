@@ -0,0 +1,271 @@
+use axum::http::{header, uri::Authority, HeaderMap};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::ClientIpHeader;
+use crate::error::{AppError, Result};
+
+// Peers whose forwarding headers are believed, and the header they set.
+// Anyone else could write whatever they like into X-Forwarded-For, so for
+// them only the socket address counts.
+#[derive(Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+    header: ClientIpHeader,
+}
+
+impl TrustedProxies {
+    pub fn new(entries: &[String]) -> Result<Self> {
+        Ok(Self {
+            networks: parse_networks(entries, "trusted proxy")?,
+            header: ClientIpHeader::default(),
+        })
+    }
+
+    pub fn with_header(mut self, header: ClientIpHeader) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+}
+
+// Accepts CIDR blocks as well as single addresses; `what` names the setting in errors
+pub fn parse_networks(entries: &[String], what: &str) -> Result<Vec<IpNet>> {
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    AppError::ConfigError(config::ConfigError::Message(format!(
+                        "invalid {} {:?}, expected an address or CIDR block",
+                        what, entry
+                    )))
+                })
+        })
+        .collect()
+}
+
+// Address of the client that made a request. The forwarding header a
+// trusted peer sets is walked from the right, skipping further trusted
+// proxies; the first other address is the client. Other forwarding headers
+// may come straight from the client and are ignored.
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>, trusted: &TrustedProxies) -> Option<IpAddr> {
+    // Without the peer's address there is no telling whether the headers can be believed
+    let peer = peer?.ip().to_canonical();
+    if !trusted.contains(peer) {
+        return Some(peer);
+    }
+
+    let hops = match trusted.header {
+        ClientIpHeader::XForwardedFor => header_list(headers, "x-forwarded-for"),
+        ClientIpHeader::Forwarded => forwarded_for(headers),
+        ClientIpHeader::XRealIp => header_list(headers, "x-real-ip"),
+    };
+    let Some(hops) = hops else {
+        return Some(peer);
+    };
+
+    // The nearest address known so far; hops are appended by each proxy
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        // "unknown" or an obfuscated name: nothing to go on past this proxy
+        let Some(ip) = parse_node(hop) else {
+            break;
+        };
+        client = ip;
+        if !trusted.contains(ip) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+// Scheme and authority the client used to reach the proxy, as a trusted
+// peer reports them in its Forwarded element, or in X-Forwarded-Proto and
+// X-Forwarded-Host unless it is set up to use Forwarded. Only the last
+// value, the one the peer itself added, is used.
+pub fn forwarded_origin(headers: &HeaderMap, peer: Option<SocketAddr>, trusted: &TrustedProxies) -> Option<String> {
+    if !trusted.contains(peer?.ip()) {
+        return None;
+    }
+    let (proto, host) = match trusted.header {
+        ClientIpHeader::Forwarded => {
+            let element = header_list(headers, header::FORWARDED.as_str())?.pop()?;
+            (forwarded_param(&element, "proto")?, forwarded_param(&element, "host")?)
+        }
+        ClientIpHeader::XForwardedFor | ClientIpHeader::XRealIp => (
+            header_list(headers, "x-forwarded-proto")?.pop()?,
+            header_list(headers, "x-forwarded-host")?.pop()?,
+        ),
+    };
+    let proto = proto.to_ascii_lowercase();
+    if !matches!(proto.as_str(), "http" | "https") || host.contains('@') || host.parse::<Authority>().is_err() {
+        return None;
+    }
+    Some(format!("{}://{}", proto, host))
+}
+
+// `for=` values of all Forwarded headers, in order
+fn forwarded_for(headers: &HeaderMap) -> Option<Vec<String>> {
+    let hops: Vec<String> = header_list(headers, header::FORWARDED.as_str())?
+        .iter()
+        .filter_map(|element| forwarded_param(element, "for"))
+        .collect();
+    (!hops.is_empty()).then_some(hops)
+}
+
+// A parameter of one Forwarded element, unquoted
+fn forwarded_param(element: &str, name: &str) -> Option<String> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        key.eq_ignore_ascii_case(name).then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+// Comma-separated entries of all instances of a header, in order
+fn header_list(headers: &HeaderMap, name: &str) -> Option<Vec<String>> {
+    let hops: Vec<String> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().to_string())
+        .filter(|hop| !hop.is_empty())
+        .collect();
+    (!hops.is_empty()).then_some(hops)
+}
+
+// An address as proxies write it: bare, with a port, or a bracketed IPv6
+// address with or without a port
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let ip = match node.strip_prefix('[') {
+        Some(rest) => rest.split_once(']')?.0.parse().ok()?,
+        None => node
+            .parse::<IpAddr>()
+            .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+            .ok()?,
+    };
+    Some(ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn trusted(header: ClientIpHeader) -> TrustedProxies {
+        let entries = ["10.0.0.0/8", "2001:db8::/32", "127.0.0.1"].map(String::from);
+        TrustedProxies::new(&entries).unwrap().with_header(header)
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn resolve(header: ClientIpHeader, peer: &str, pairs: &[(&'static str, &'static str)]) -> IpAddr {
+        client_ip(&headers(pairs), Some(peer.parse().unwrap()), &trusted(header)).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn walks_trusted_hops_from_the_right() {
+        let xff = [("x-forwarded-for", "203.0.113.7, 10.0.0.2, 10.0.0.3")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "10.0.0.1:443", &xff), ip("203.0.113.7"));
+
+        // Every hop trusted: the leftmost one is as far as the chain goes
+        let all_trusted = [("x-forwarded-for", "10.0.0.9, 10.0.0.2")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "10.0.0.1:443", &all_trusted), ip("10.0.0.9"));
+
+        // Several header lines read as one list
+        let split = [("x-forwarded-for", "198.51.100.1"), ("x-forwarded-for", "203.0.113.7, 10.0.0.2")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "10.0.0.1:443", &split), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn spoofed_leftmost_entries_are_skipped() {
+        // The client sent "X-Forwarded-For: 1.2.3.4"; the proxy appended its address
+        let spoofed = [("x-forwarded-for", "1.2.3.4, 10.0.0.5, 203.0.113.7")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "10.0.0.1:443", &spoofed), ip("203.0.113.7"));
+
+        // An unparsable hop ends the walk at the last address known
+        let garbage = [("x-forwarded-for", "1.2.3.4, unknown, 10.0.0.2")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "10.0.0.1:443", &garbage), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        // A Forwarded header the client made up, passed through by a proxy
+        // that only appends to X-Forwarded-For
+        let both = [("forwarded", "for=1.2.3.4"), ("x-forwarded-for", "203.0.113.7"), ("x-real-ip", "5.6.7.8")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "10.0.0.1:443", &both), ip("203.0.113.7"));
+        assert_eq!(resolve(ClientIpHeader::Forwarded, "10.0.0.1:443", &both), ip("1.2.3.4"));
+        assert_eq!(resolve(ClientIpHeader::XRealIp, "10.0.0.1:443", &both), ip("5.6.7.8"));
+
+        // The configured header missing: the peer is the client
+        let forwarded_only = [("forwarded", "for=1.2.3.4")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "10.0.0.1:443", &forwarded_only), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn reads_ipv6_bracketed_and_port_forms() {
+        let xff = [("x-forwarded-for", "2001:db9::7, 10.0.0.2:5000")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "10.0.0.1:443", &xff), ip("2001:db9::7"));
+
+        let bracketed = [("x-forwarded-for", "[2001:db9::7]:4711, [2001:db8::2]")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "[2001:db8::1]:443", &bracketed), ip("2001:db9::7"));
+
+        let with_port = [("x-forwarded-for", "203.0.113.7:51234")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "10.0.0.1:443", &with_port), ip("203.0.113.7"));
+
+        let forwarded = [("forwarded", "for=\"[2001:db9::7]:4711\";proto=https, for=10.0.0.2")];
+        assert_eq!(resolve(ClientIpHeader::Forwarded, "10.0.0.1:443", &forwarded), ip("2001:db9::7"));
+
+        // IPv4-mapped peers are matched as IPv4
+        let mapped = [("x-forwarded-for", "203.0.113.7")];
+        assert_eq!(resolve(ClientIpHeader::XForwardedFor, "[::ffff:10.0.0.1]:443", &mapped), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn untrusted_peers_headers_are_ignored() {
+        let spoofed = [("x-forwarded-for", "1.2.3.4"), ("forwarded", "for=1.2.3.4"), ("x-real-ip", "1.2.3.4")];
+        for header in [ClientIpHeader::XForwardedFor, ClientIpHeader::Forwarded, ClientIpHeader::XRealIp] {
+            assert_eq!(resolve(header, "198.51.100.9:443", &spoofed), ip("198.51.100.9"));
+        }
+        assert_eq!(client_ip(&headers(&spoofed), None, &trusted(ClientIpHeader::XForwardedFor)), None);
+    }
+
+    #[test]
+    fn origin_follows_the_configured_header() {
+        let pairs = headers(&[
+            ("forwarded", "proto=http;host=evil.example"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "gh.example.com"),
+        ]);
+        let peer = Some("127.0.0.1:443".parse().unwrap());
+        assert_eq!(
+            forwarded_origin(&pairs, peer, &trusted(ClientIpHeader::XForwardedFor)).as_deref(),
+            Some("https://gh.example.com")
+        );
+        assert_eq!(
+            forwarded_origin(&pairs, peer, &trusted(ClientIpHeader::Forwarded)).as_deref(),
+            Some("http://evil.example")
+        );
+        assert_eq!(forwarded_origin(&pairs, Some("198.51.100.9:443".parse().unwrap()), &trusted(ClientIpHeader::XForwardedFor)), None);
+
+        let userinfo = headers(&[("x-forwarded-proto", "https"), ("x-forwarded-host", "user@gh.example.com")]);
+        assert_eq!(forwarded_origin(&userinfo, peer, &trusted(ClientIpHeader::XForwardedFor)), None);
+    }
+}
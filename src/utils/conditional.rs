@@ -0,0 +1,35 @@
+use axum::http::{header, HeaderMap};
+use std::time::SystemTime;
+
+// Whether a client's conditional GET is satisfied by the stored validators, so
+// a 304 can be sent instead of the body. If-None-Match takes precedence over
+// If-Modified-Since, as in RFC 9110 section 13.2.2.
+pub fn is_not_modified(request: &HeaderMap, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+    if let Some(if_none_match) = request.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        return etag.is_some_and(|etag| none_match_hits(if_none_match, etag));
+    }
+
+    let since = request
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_http_date);
+    let modified = last_modified.and_then(parse_http_date);
+    matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+// If-None-Match uses the weak comparison: W/"x" and "x" are the same tag
+fn none_match_hits(if_none_match: &str, etag: &str) -> bool {
+    let etag = strip_weak(etag);
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || strip_weak(candidate) == etag)
+}
+
+fn strip_weak(tag: &str) -> &str {
+    tag.trim().strip_prefix("W/").unwrap_or(tag.trim())
+}
+
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.trim()).ok()
+}
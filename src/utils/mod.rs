@@ -1,8 +1,15 @@
 use crate::config::Config;
-use axum::http::HeaderMap;
 
+mod client_ip;
+mod conditional;
+mod proxy_protocol;
+mod range;
 mod regex;
 mod url;
+pub use client_ip::*;
+pub use conditional::*;
+pub use proxy_protocol::*;
+pub use range::*;
 pub use regex::*;
 pub use url::*;
 
@@ -23,15 +30,7 @@ pub fn process_url(path: String, config: &Config) -> String {
     path
 }
 
-// Get client IP from request headers
-pub fn get_client_ip(headers: &HeaderMap) -> &str {
-    headers
-        .get("x-forwarded-for")
-        .and_then(|hv| hv.to_str().ok())
-        .or_else(|| {
-            headers
-                .get("x-real-ip")
-                .and_then(|hv| hv.to_str().ok())
-        })
-        .unwrap_or("unknown")
-}
\ No newline at end of file
+// Lowercase hex form of a digest, as LFS object ids and cache file names use
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,239 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+// Start of every version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// Longest possible version 1 header, CRLF included
+const V1_MAX_LENGTH: usize = 107;
+
+// Read the PROXY protocol header (version 1 or 2) a load balancer sends ahead
+// of the connection's own data and return the client address it names. None
+// means the balancer opened the connection itself, e.g. for a health check,
+// or did not know the source; the socket's peer address then stands.
+// Nothing past the header is consumed.
+pub async fn read_proxy_header<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        return read_v2(reader).await;
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("connection does not start with a PROXY protocol header"));
+    }
+    let mut line = start.to_vec();
+    (&mut *reader)
+        .take((V1_MAX_LENGTH - start.len()) as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    parse_v1(&line)
+}
+
+// "PROXY TCP4 <src> <dst> <src port> <dst port>\r\n", or "PROXY UNKNOWN ...\r\n"
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let malformed = || invalid("malformed PROXY v1 header");
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or_else(malformed)?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| malformed())?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(malformed());
+            }
+            let port: u16 = port.parse().map_err(|_| malformed())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(malformed()),
+    }
+}
+
+// The binary header after its signature: version and command, address
+// family, then a length-prefixed block of addresses and optional TLVs
+async fn read_v2<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut head = [0u8; 4];
+    reader.read_exact(&mut head).await?;
+    let [version_command, family, length @ ..] = head;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    // Always read the whole block so the connection's data starts right after
+    let mut block = vec![0u8; u16::from_be_bytes(length) as usize];
+    reader.read_exact(&mut block).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: the balancer's own connection
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+    // The high nibble is the address family; whether it carried TCP or UDP
+    // makes no difference here
+    match family >> 4 {
+        0x1 if block.len() >= 12 => {
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([block[8], block[9]]))))
+        }
+        0x2 if block.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&block[..16]);
+            let ip = Ipv6Addr::from(octets);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([block[32], block[33]]))))
+        }
+        0x1 | 0x2 => Err(invalid("PROXY v2 address block too short")),
+        // Unspecified or a Unix socket: no address to go by
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    // Parse a header from `input` and return it with the bytes left after it
+    async fn parse(input: &[u8]) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut reader = BufReader::new(input);
+        let header = read_proxy_header(&mut reader).await;
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        (header, rest)
+    }
+
+    fn addr(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    fn v2(command: u8, family: u8, block: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(block.len() as u16).to_be_bytes());
+        header.extend_from_slice(block);
+        header
+    }
+
+    fn kind(result: Result<Option<SocketAddr>>) -> ErrorKind {
+        result.unwrap_err().kind()
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (header, rest) = parse(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(header.unwrap(), addr("203.0.113.7:51234"));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (header, rest) = parse(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 443\r\nGET").await;
+        assert_eq!(header.unwrap(), addr("[2001:db8::7]:51234"));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let (header, rest) = parse(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\nGET").await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        for line in [
+            &b"PROXY TCP4 nope 10.0.0.1 1 2\r\n"[..],
+            b"PROXY TCP4 2001:db8::7 10.0.0.1 1 2\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 70000 2\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 1\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 1 2\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\n\r\n",
+        ] {
+            assert_eq!(kind(parse(line).await.0), ErrorKind::InvalidData, "{:?}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_over_length_without_crlf() {
+        let mut line = b"PROXY TCP4 ".to_vec();
+        line.resize(200, b'1');
+        let (header, rest) = parse(&line).await;
+        assert_eq!(kind(header), ErrorKind::InvalidData);
+        // Reading stopped at the longest header allowed
+        assert_eq!(rest.len(), 200 - V1_MAX_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let mut input = v2(0x0, 0x11, &[0; 12]);
+        input.extend_from_slice(b"GET");
+        let (header, rest) = parse(&input).await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv4() {
+        let mut block = vec![198, 51, 100, 9, 10, 0, 0, 1];
+        block.extend_from_slice(&40000u16.to_be_bytes());
+        block.extend_from_slice(&443u16.to_be_bytes());
+        // A TLV after the addresses is skipped
+        block.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        let mut input = v2(0x1, 0x11, &block);
+        input.extend_from_slice(b"GET");
+        let (header, rest) = parse(&input).await;
+        assert_eq!(header.unwrap(), addr("198.51.100.9:40000"));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv6() {
+        let source: std::net::Ipv6Addr = "2001:db8::99".parse().unwrap();
+        let mut block = source.octets().to_vec();
+        block.extend_from_slice(&[0; 16]);
+        block.extend_from_slice(&40000u16.to_be_bytes());
+        block.extend_from_slice(&443u16.to_be_bytes());
+        let (header, rest) = parse(&v2(0x1, 0x21, &block)).await;
+        assert_eq!(header.unwrap(), addr("[2001:db8::99]:40000"));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v2_unix_or_unspecified_family() {
+        let (header, _) = parse(&v2(0x1, 0x00, &[])).await;
+        assert_eq!(header.unwrap(), None);
+        let (header, _) = parse(&v2(0x1, 0x31, &[0; 216])).await;
+        assert_eq!(header.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_address_block_too_short() {
+        let (header, _) = parse(&v2(0x1, 0x11, &[0; 11])).await;
+        assert_eq!(kind(header), ErrorKind::InvalidData);
+        let (header, _) = parse(&v2(0x1, 0x21, &[0; 35])).await;
+        assert_eq!(kind(header), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2_bad_version_or_command() {
+        let mut wrong_version = v2(0x1, 0x11, &[0; 12]);
+        wrong_version[12] = 0x11;
+        assert_eq!(kind(parse(&wrong_version).await.0), ErrorKind::InvalidData);
+
+        let wrong_command = v2(0x2, 0x11, &[0; 12]);
+        assert_eq!(kind(parse(&wrong_command).await.0), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn short_read() {
+        assert_eq!(kind(parse(b"PROXY").await.0), ErrorKind::UnexpectedEof);
+        let truncated = v2(0x1, 0x11, &[0; 12]);
+        assert_eq!(kind(parse(&truncated[..20]).await.0), ErrorKind::UnexpectedEof);
+        assert_eq!(kind(parse(&V2_SIGNATURE[..]).await.0), ErrorKind::UnexpectedEof);
+    }
+}
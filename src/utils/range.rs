@@ -0,0 +1,143 @@
+// Single byte range resolved against a known entity length (inclusive bounds)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+// Outcome of evaluating a Range header against a representation
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    // No usable Range header, serve the full body
+    Full,
+    // Serve 206 with this range
+    Partial(ByteRange),
+    // Serve 416 with `Content-Range: bytes */len`
+    Unsatisfiable,
+}
+
+// Parse a `Range: bytes=...` header value against an entity of `total` bytes.
+// Only single ranges are honoured; multipart ranges and unknown units fall
+// back to the full body, which RFC 9110 allows.
+pub fn parse_range(header: &str, total: u64) -> RangeRequest {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // Suffix range: the last N bytes
+        let Ok(suffix) = end.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        if suffix == 0 || total == 0 {
+            return RangeRequest::Unsatisfiable;
+        }
+        ByteRange { start: total.saturating_sub(suffix), end: total - 1 }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        let end = if end.is_empty() {
+            u64::MAX
+        } else {
+            match end.parse::<u64>() {
+                Ok(end) if end >= start => end,
+                _ => return RangeRequest::Full,
+            }
+        };
+        if start >= total {
+            return RangeRequest::Unsatisfiable;
+        }
+        ByteRange { start, end: end.min(total - 1) }
+    };
+
+    RangeRequest::Partial(range)
+}
+
+// Evaluate an If-Range precondition. A strong ETag must match exactly, a date
+// must equal the stored Last-Modified; anything else means "send everything".
+pub fn if_range_matches(if_range: &str, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') {
+        etag.is_some_and(|etag| etag == if_range)
+    } else if if_range.starts_with("W/") {
+        false
+    } else {
+        last_modified.is_some_and(|lm| lm == if_range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(start: u64, end: u64) -> RangeRequest {
+        RangeRequest::Partial(ByteRange { start, end })
+    }
+
+    #[test]
+    fn open_ended_range_runs_to_the_end() {
+        assert_eq!(parse_range("bytes=0-", 1000), partial(0, 999));
+        assert_eq!(parse_range("bytes=500-", 1000), partial(500, 999));
+        assert_eq!(parse_range("bytes=500-99999", 1000), partial(500, 999));
+        assert_eq!(parse_range("bytes=10-19", 1000), partial(10, 19));
+        assert_eq!(ByteRange { start: 10, end: 19 }.content_range(1000), "bytes 10-19/1000");
+    }
+
+    #[test]
+    fn suffix_range_takes_the_last_bytes() {
+        assert_eq!(parse_range("bytes=-500", 1000), partial(500, 999));
+        // Longer than the body: the whole body, still as a 206
+        assert_eq!(parse_range("bytes=-5000", 1000), partial(0, 999));
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-1", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn start_past_the_end_is_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=2000-2999", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn unsupported_ranges_fall_back_to_the_full_body() {
+        assert_eq!(parse_range("bytes=0-99,200-299", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=-100, 0-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-99", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=99-10", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=a-b", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=100", 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn if_range_etag_must_match_strongly() {
+        assert!(if_range_matches("\"v1\"", Some("\"v1\""), None));
+        assert!(!if_range_matches("\"v2\"", Some("\"v1\""), None));
+        assert!(!if_range_matches("\"v1\"", None, None));
+        // Weak validators never satisfy If-Range, on either side
+        assert!(!if_range_matches("W/\"v1\"", Some("W/\"v1\""), None));
+        assert!(!if_range_matches("\"v1\"", Some("W/\"v1\""), None));
+    }
+
+    #[test]
+    fn if_range_date_must_equal_last_modified() {
+        let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        assert!(if_range_matches(last_modified, Some("\"v1\""), Some(last_modified)));
+        assert!(!if_range_matches("Thu, 22 Oct 2015 07:28:00 GMT", None, Some(last_modified)));
+        assert!(!if_range_matches(last_modified, Some("\"v1\""), None));
+    }
+}
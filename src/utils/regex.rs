@@ -37,6 +37,11 @@ pub static GITLAB_BLOBS: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"^(?:https?:\/\/)?gitlab\.com\/.+?\/.+?\/(?:-/)?blob\/.*$").expect("Invalid regex GITLAB_BLOBS")
 });
 
+pub static GITLAB_GIT_INFO: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:https?:\/\/)?gitlab\.com\/.+?\/.+?\/(?:info\/refs|git-upload-pack|git-receive-pack)(?:\?.*)?$")
+        .expect("Invalid regex GITLAB_GIT_INFO")
+});
+
 // Bitbucket patterns
 pub static BITBUCKET_REPO: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"^(?:https?:\/\/)?bitbucket\.org\/.+?\/.+?\/(?:get|downloads).*").expect("Invalid regex BITBUCKET_REPO")
@@ -44,4 +49,69 @@ pub static BITBUCKET_REPO: Lazy<Regex> = Lazy::new(|| {
 
 pub static BITBUCKET_RAW: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"^(?:https?:\/\/)?bitbucket\.org\/.+?\/.+?\/(?:raw|src)\/.*$").expect("Invalid regex BITBUCKET_RAW")
-});
\ No newline at end of file
+});
+pub static BITBUCKET_GIT_INFO: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:https?:\/\/)?bitbucket\.org\/.+?\/.+?\/(?:info\/refs|git-upload-pack|git-receive-pack)(?:\?.*)?$")
+        .expect("Invalid regex BITBUCKET_GIT_INFO")
+});
+
+// Git smart HTTP endpoints on any service
+pub static GIT_SMART_HTTP: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\/(?:info\/refs|git-upload-pack|git-receive-pack)(?:\?.*)?$").expect("Invalid regex GIT_SMART_HTTP")
+});
+
+pub static GIT_RECEIVE_PACK: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:\/git-receive-pack(?:\?.*)?$|[?&]service=git-receive-pack(?:&|$))").expect("Invalid regex GIT_RECEIVE_PACK")
+});
+
+// Git LFS patterns
+pub static GITHUB_LFS_BATCH: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:https?:\/\/)?github\.com\/.+?\/.+?\/info\/lfs\/objects\/batch$").expect("Invalid regex GITHUB_LFS_BATCH")
+});
+
+pub static GITLAB_LFS_BATCH: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:https?:\/\/)?gitlab\.com\/.+?\/.+?\/info\/lfs\/objects\/batch$").expect("Invalid regex GITLAB_LFS_BATCH")
+});
+
+pub static BITBUCKET_LFS_BATCH: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:https?:\/\/)?bitbucket\.org\/.+?\/.+?\/info\/lfs\/objects\/batch$").expect("Invalid regex BITBUCKET_LFS_BATCH")
+});
+
+pub static GITHUB_LFS_OBJECTS: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:https?:\/\/)?(?:github-cloud\.githubusercontent\.com|github-cloud\.s3\.amazonaws\.com|media\.githubusercontent\.com)\/.+$")
+        .expect("Invalid regex GITHUB_LFS_OBJECTS")
+});
+
+pub static GITLAB_LFS_OBJECTS: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:https?:\/\/)?gitlab\.com\/.+?\/.+?\/gitlab-lfs\/objects\/[0-9a-f]{64}(?:[\/?].*)?$")
+        .expect("Invalid regex GITLAB_LFS_OBJECTS")
+});
+
+// LFS storage URLs that name an object by its SHA-256 id, capturing the id.
+// Other paths on these hosts (media.githubusercontent.com in particular)
+// are chosen by repository owners and say nothing about the content.
+pub static LFS_OID: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:https?:\/\/)?(?:(?:github-cloud\.githubusercontent\.com|github-cloud\.s3\.amazonaws\.com)\/alambic\/media\/\d+\/[0-9a-f]{2}\/[0-9a-f]{2}|gitlab\.com\/.+?\/.+?\/gitlab-lfs\/objects)\/([0-9a-f]{64})$")
+        .expect("Invalid regex LFS_OID")
+});
+
+// Look up a URL class by the name of its pattern, e.g. "github_releases"
+pub fn url_class(name: &str) -> Option<&'static Regex> {
+    let regex: &'static Lazy<Regex> = match name.to_ascii_uppercase().as_str() {
+        "GITHUB_RELEASES" => &GITHUB_RELEASES,
+        "GITHUB_BLOB_RAW" => &GITHUB_BLOB_RAW,
+        "GITHUB_GIT_INFO" => &GITHUB_GIT_INFO,
+        "GITHUB_RAW" => &GITHUB_RAW,
+        "GITHUB_GIST" => &GITHUB_GIST,
+        "GITHUB_TAGS" => &GITHUB_TAGS,
+        "GITLAB_PROJECTS" => &GITLAB_PROJECTS,
+        "GITLAB_RAW" => &GITLAB_RAW,
+        "GITLAB_BLOBS" => &GITLAB_BLOBS,
+        "GITLAB_GIT_INFO" => &GITLAB_GIT_INFO,
+        "BITBUCKET_REPO" => &BITBUCKET_REPO,
+        "BITBUCKET_RAW" => &BITBUCKET_RAW,
+        "BITBUCKET_GIT_INFO" => &BITBUCKET_GIT_INFO,
+        _ => return None,
+    };
+    Some(Lazy::force(regex))
+}
@@ -1,6 +1,26 @@
 use crate::utils::regex::*;
 use crate::config::Config;
 
+// Upstream service a URL belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GitService {
+    GitHub,
+    GitLab,
+    Bitbucket,
+}
+
+// Work out which service a proxied URL belongs to. Storage hosts count as the
+// service that hands them out; anything unknown is treated as GitHub.
+pub fn git_service(path: &str) -> GitService {
+    match host_of(path).map(|host| host.to_ascii_lowercase()) {
+        Some(host) if host == "gitlab.com" || host.ends_with(".gitlab.com") => GitService::GitLab,
+        Some(host) if host == "bitbucket.org" || host.ends_with(".bitbucket.org") || host == "bbuseruploads.s3.amazonaws.com" => {
+            GitService::Bitbucket
+        }
+        _ => GitService::GitHub,
+    }
+}
+
 // Check if URL is a supported GitHub URL
 pub fn is_github_url(path: &str) -> bool {
     GITHUB_RELEASES.is_match(path)
@@ -17,6 +37,7 @@ pub fn is_gitlab_url(path: &str, config: &Config) -> bool {
         GITLAB_PROJECTS.is_match(path)
         || GITLAB_RAW.is_match(path)
         || GITLAB_BLOBS.is_match(path)
+        || GITLAB_GIT_INFO.is_match(path)
     )
 }
 
@@ -25,10 +46,119 @@ pub fn is_bitbucket_url(path: &str, config: &Config) -> bool {
     config.git_services.bitbucket_enabled && (
         BITBUCKET_REPO.is_match(path)
         || BITBUCKET_RAW.is_match(path)
+        || BITBUCKET_GIT_INFO.is_match(path)
+    )
+}
+
+// Check if URL is a Git LFS batch endpoint on an enabled service
+pub fn is_lfs_batch(path: &str, config: &Config) -> bool {
+    config.lfs.enabled && (
+        GITHUB_LFS_BATCH.is_match(path)
+        || (config.git_services.gitlab_enabled && GITLAB_LFS_BATCH.is_match(path))
+        || (config.git_services.bitbucket_enabled && BITBUCKET_LFS_BATCH.is_match(path))
     )
 }
 
+// Check if URL is a Git LFS object download on a known storage host
+pub fn is_lfs_object(path: &str, config: &Config) -> bool {
+    config.lfs.enabled && (
+        GITHUB_LFS_OBJECTS.is_match(path)
+        || (config.git_services.gitlab_enabled && GITLAB_LFS_OBJECTS.is_match(path))
+    )
+}
+
+// Extract the object id from a content-addressed LFS storage URL
+pub fn lfs_oid(path: &str) -> Option<&str> {
+    let path = path.split('?').next().unwrap_or(path);
+    LFS_OID
+        .captures(path)
+        .and_then(|caps| caps.get(1))
+        .map(|oid| oid.as_str())
+}
+
 // Check if URL is any supported Git service URL
 pub fn is_supported_url(path: &str, config: &Config) -> bool {
-    is_github_url(path) || is_gitlab_url(path, config) || is_bitbucket_url(path, config)
-}
\ No newline at end of file
+    is_github_url(path)
+        || is_gitlab_url(path, config)
+        || is_bitbucket_url(path, config)
+        || is_lfs_batch(path, config)
+        || is_lfs_object(path, config)
+        || is_redirect_target(path, config)
+}
+// Check if URL is a git smart HTTP endpoint (ref advertisement or pack negotiation)
+pub fn is_git_smart_http(path: &str) -> bool {
+    GIT_SMART_HTTP.is_match(path)
+}
+
+// Check if URL is part of a git push, which the proxy refuses
+pub fn is_git_push(path: &str) -> bool {
+    GIT_RECEIVE_PACK.is_match(path)
+}
+
+// Check if a host is on an allowlist
+pub fn is_allowed_host(host: &str, allowed_hosts: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+    allowed_hosts.iter().any(|allowed| {
+        let allowed = allowed.to_ascii_lowercase();
+        match allowed.strip_prefix('.') {
+            Some(domain) => host == domain || host.ends_with(&allowed),
+            None => host == allowed,
+        }
+    })
+}
+
+// Extract the host part of a proxied path, which may or may not carry a scheme
+pub fn host_of(path: &str) -> Option<&str> {
+    let rest = path
+        .strip_prefix("https://")
+        .or_else(|| path.strip_prefix("http://"))
+        .unwrap_or(path);
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = host.split(':').next()?;
+    (!host.is_empty()).then_some(host)
+}
+
+// Check if URL points at an allowlisted redirect host (e.g. release asset storage)
+pub fn is_redirect_target(path: &str, config: &Config) -> bool {
+    host_of(path).is_some_and(|host| is_allowed_host(host, &config.redirect.allowed_hosts))
+}
+
+// Query parameters that only carry analytics and never change the response
+const TRACKING_PARAMS: [&str; 10] = [
+    "fbclid", "gclid", "dclid", "msclkid", "yclid", "mc_cid", "mc_eid", "_ga", "_gl", "igshid",
+];
+
+fn is_tracking_param(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.starts_with("utm_") || TRACKING_PARAMS.contains(&name.as_str())
+}
+
+// Canonical form of a requested URL: https when no scheme is given, lower-case
+// scheme and host, no default port or fragment, and no tracking parameters.
+// Anything that doesn't parse as a URL is returned unchanged.
+pub fn normalize_url(path: &str) -> String {
+    let has_scheme = ["https://", "http://"]
+        .iter()
+        .any(|scheme| path.get(..scheme.len()).is_some_and(|p| p.eq_ignore_ascii_case(scheme)));
+    let with_scheme = match has_scheme {
+        true => path.to_string(),
+        false => format!("https://{}", path),
+    };
+    let Ok(mut url) = reqwest::Url::parse(&with_scheme) else {
+        return path.to_string();
+    };
+
+    url.set_fragment(None);
+    // Filter the raw pairs so the remaining parameters keep their exact encoding
+    if let Some(query) = url.query() {
+        let kept = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter(|pair| !is_tracking_param(pair.split('=').next().unwrap_or_default()))
+            .collect::<Vec<_>>()
+            .join("&");
+        url.set_query((!kept.is_empty()).then_some(kept.as_str()));
+    }
+    url.to_string()
+}